@@ -0,0 +1,40 @@
+//! Dispatching one large batch, where moving entries instead of cloning them counts.
+
+use std::{hint::black_box, net::SocketAddr, sync::Arc};
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, Criterion};
+use http_body_util::Full;
+use hyper::Request;
+use json_rpc_server::{handle_hyper_request, HandlerMap, ServerConfig};
+use serde_json::json;
+
+fn batch(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let mut methods = HandlerMap::new();
+    methods.insert("echo", |params| async move { Ok(params) });
+    let handle = Arc::new(methods);
+    let config = Arc::new(ServerConfig::default());
+    let peer = SocketAddr::from(([127, 0, 0, 1], 0));
+
+    let entries = (0..1000)
+        .map(|id| json!({"jsonrpc": "2.0", "id": id, "method": "echo", "params": {"id": id, "data": "x".repeat(64)}}))
+        .collect::<Vec<_>>();
+    let body = Bytes::from(serde_json::to_vec(&entries).unwrap());
+
+    c.bench_function("batch of 1000", |b| {
+        b.iter(|| {
+            let request = Request::post("/").body(Full::new(body.clone())).unwrap();
+            let resp = rt.block_on(handle_hyper_request(
+                request,
+                handle.clone(),
+                config.clone(),
+                peer,
+            ));
+            black_box(resp.unwrap())
+        })
+    });
+}
+
+criterion_group!(benches, batch);
+criterion_main!(benches);
@@ -0,0 +1,52 @@
+//! Reading one request with large params, which are parsed straight into the
+//! handler's type instead of through a `serde_json::Value`.
+
+use std::{hint::black_box, net::SocketAddr, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, Criterion};
+use http_body_util::Full;
+use hyper::Request;
+use json_rpc_server::{handle_hyper_request, Handle, RPCResult, ServerConfig};
+use serde_json::json;
+
+/// Sums the numbers it is given.
+struct Sum;
+
+#[async_trait]
+impl Handle for Sum {
+    type Request = Vec<u64>;
+    type Response = u64;
+
+    async fn handle(&self, _: &str, req: Option<Vec<u64>>) -> RPCResult<Option<u64>> {
+        Ok(Some(req.unwrap_or_default().iter().sum()))
+    }
+}
+
+fn single(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let handle = Arc::new(Sum);
+    let config = Arc::new(ServerConfig::default());
+    let peer = SocketAddr::from(([127, 0, 0, 1], 0));
+
+    let params = (0..100_000).collect::<Vec<u64>>();
+    let req = json!({"jsonrpc": "2.0", "id": 1, "method": "sum", "params": params});
+    let body = Bytes::from(serde_json::to_vec(&req).unwrap());
+
+    c.bench_function("single request with 100000 params", |b| {
+        b.iter(|| {
+            let request = Request::post("/").body(Full::new(body.clone())).unwrap();
+            let resp = rt.block_on(handle_hyper_request(
+                request,
+                handle.clone(),
+                config.clone(),
+                peer,
+            ));
+            black_box(resp.unwrap())
+        })
+    });
+}
+
+criterion_group!(benches, single);
+criterion_main!(benches);
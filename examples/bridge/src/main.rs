@@ -0,0 +1,57 @@
+#![deny(warnings, unused_crate_dependencies)]
+
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use json_rpc_server::{serve_with_config, Handle, RPCError, ServerConfig};
+use serde_json::Value;
+
+pub struct EchoHandle;
+
+#[async_trait]
+impl Handle for EchoHandle {
+    type Request = Value;
+    type Response = Value;
+
+    async fn handle(
+        &self,
+        method: &str,
+        req: Option<Self::Request>,
+    ) -> std::result::Result<Option<Self::Response>, RPCError> {
+        match method {
+            "echo" => Ok(req),
+            _ => Err(RPCError::unknown_method()),
+        }
+    }
+}
+
+/// Upgrades a legacy 1.0 request: adds the missing `jsonrpc` field and maps the old
+/// `ping` method onto `echo`.
+fn upgrade(mut req: Value) -> Value {
+    if let Some(entries) = req.as_array_mut() {
+        entries.iter_mut().for_each(|e| *e = upgrade(e.take()));
+        return req;
+    }
+    if let Some(obj) = req.as_object_mut() {
+        obj.entry("jsonrpc").or_insert_with(|| Value::from("2.0"));
+        if obj.get("method").and_then(Value::as_str) == Some("ping") {
+            obj.insert(String::from("method"), Value::from("echo"));
+        }
+    }
+    req
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    if std::env::var_os("RUST_LOG").is_none() {
+        std::env::set_var("RUST_LOG", "info");
+    }
+    env_logger::init();
+    let addr: SocketAddr = "127.0.0.1:8082".parse()?;
+    let config = ServerConfig {
+        request_transform: Some(Arc::new(upgrade)),
+        ..Default::default()
+    };
+    serve_with_config(&addr, EchoHandle, config).await
+}
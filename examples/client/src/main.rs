@@ -1,7 +1,7 @@
 #![deny(warnings, unused_crate_dependencies)]
 
 use anyhow::Result;
-use json_rpc_server::call;
+use json_rpc_server::{call, Auth, RpcClient};
 use serde_json::{json, Value};
 
 #[tokio::main]
@@ -18,5 +18,22 @@ async fn main() -> Result<()> {
         .unwrap();
     println!("{:?}", ret);
 
+    // Every call through this client sends the token, unless it passes its own.
+    let client = RpcClient::builder()
+        .url("http://127.0.0.1:8080")
+        .bearer("example-token")
+        .build()?;
+    let ret = client
+        .call::<Value, Value>("example_fn1", &json!([20, false]))
+        .await
+        .unwrap();
+    println!("{:?}", ret);
+
+    let ret = client
+        .call_with_auth::<Value, Value>("example_fn2", &json!([200,]), &Auth::bearer("other"))
+        .await
+        .unwrap();
+    println!("{:?}", ret);
+
     Ok(())
 }
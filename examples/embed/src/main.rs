@@ -0,0 +1,57 @@
+#![deny(warnings, unused_crate_dependencies)]
+
+//! Mounts JSON-RPC as the `/rpc` route of an axum app that serves other routes too.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::Result;
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{any, get},
+    Router,
+};
+use json_rpc_server::{handle_hyper_request, HandlerMap, ServerConfig};
+use serde_json::json;
+use tokio::net::TcpListener;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    if std::env::var_os("RUST_LOG").is_none() {
+        std::env::set_var("RUST_LOG", "info");
+    }
+    env_logger::init();
+
+    let mut methods = HandlerMap::new();
+    methods.insert("ping", |_| async { Ok(Some(json!("pong"))) });
+    let handle = Arc::new(methods);
+    let config = Arc::new(ServerConfig::default());
+
+    // `any` rather than `post`, so WebSocket upgrades reach the route too.
+    let rpc = move |ConnectInfo(peer): ConnectInfo<SocketAddr>, req: Request| {
+        let (handle, config) = (handle.clone(), config.clone());
+        async move {
+            match handle_hyper_request(req, handle, config, peer).await {
+                Ok(resp) => resp.map(axum::body::Body::new),
+                Err(e) => {
+                    log::error!("Failed to answer JSON-RPC request: {:?}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                }
+            }
+        }
+    };
+    let app = Router::new()
+        .route("/rpc", any(rpc))
+        .route("/", get(|| async { "Hello from the host app" }));
+
+    let addr: SocketAddr = "127.0.0.1:8080".parse()?;
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("Serving /rpc and / on http://{}", addr);
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,53 @@
+#![deny(warnings, unused_crate_dependencies)]
+
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use json_rpc_server::{Handle, RPCError, Server};
+use serde_json::Value;
+
+pub struct EchoHandle;
+
+#[async_trait]
+impl Handle for EchoHandle {
+    type Request = Value;
+    type Response = Value;
+
+    async fn handle(
+        &self,
+        _method: &str,
+        req: Option<Self::Request>,
+    ) -> std::result::Result<Option<Self::Response>, RPCError> {
+        Ok(req)
+    }
+}
+
+async fn heartbeat() {
+    loop {
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        log::info!("still serving");
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    if std::env::var_os("RUST_LOG").is_none() {
+        std::env::set_var("RUST_LOG", "info");
+    }
+    env_logger::init();
+    let addr: SocketAddr = "127.0.0.1:8083".parse()?;
+
+    // The server is just another future: run it next to the rest of the application and
+    // let Ctrl-C stop it gracefully, waiting for the requests already in flight.
+    let server = Server::new(EchoHandle);
+    let shutdown = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    tokio::select! {
+        r = server.serve_with_shutdown(&addr, shutdown) => r?,
+        _ = heartbeat() => {}
+    }
+    log::info!("server stopped");
+    Ok(())
+}
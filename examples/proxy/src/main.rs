@@ -0,0 +1,40 @@
+#![deny(warnings, unused_crate_dependencies)]
+
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use json_rpc_server::{call, serve, Handle, RPCError};
+use serde_json::{value::RawValue, Value};
+
+/// Forwards every call to `upstream` and hands back the upstream result bytes untouched.
+pub struct ProxyHandle {
+    upstream: String,
+}
+
+#[async_trait]
+impl Handle for ProxyHandle {
+    type Request = Value;
+    type Response = Box<RawValue>;
+
+    async fn handle(
+        &self,
+        method: &str,
+        req: Option<Self::Request>,
+    ) -> std::result::Result<Option<Self::Response>, RPCError> {
+        call::<Option<Value>, Box<RawValue>>(&self.upstream, method, &req, None).await
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    if std::env::var_os("RUST_LOG").is_none() {
+        std::env::set_var("RUST_LOG", "info");
+    }
+    env_logger::init();
+    let addr: SocketAddr = "127.0.0.1:8081".parse()?;
+    let handle = ProxyHandle {
+        upstream: String::from("http://127.0.0.1:8080"),
+    };
+    serve(&addr, handle).await
+}
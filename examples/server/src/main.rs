@@ -1,14 +1,16 @@
 #![deny(warnings, unused_crate_dependencies)]
 
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Duration};
 
 use anyhow::Result;
 use async_trait::async_trait;
-use json_rpc_server::{serve, Handle, RPCError, RPCResult};
+use json_rpc_server::{serve, Cache, Handle, RPCError, RPCResult};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-pub struct ExampleHandle;
+pub struct ExampleHandle {
+    cache: Cache<u64, Value>,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
@@ -47,7 +49,6 @@ impl Handle for ExampleHandle {
         match method {
             "example_fn1" => {
                 let param = req
-                    .clone()
                     .ok_or(RPCError::invalid_params())
                     .and_then(|v| v.into_param1())?;
                 serde_json::to_value(param)
@@ -56,12 +57,16 @@ impl Handle for ExampleHandle {
             }
             "example_fn2" => {
                 let param = req
-                    .clone()
                     .ok_or(RPCError::invalid_params())
                     .and_then(|v| v.into_param2())?;
-                serde_json::to_value(param)
-                    .map(|v| Some(v))
-                    .map_err(|e| RPCError::internal_error(format!("{}", e)))
+                // Stands in for an expensive computation, reused for repeated params.
+                self.cache
+                    .get_or_try_compute(param.0, || async {
+                        serde_json::to_value(param)
+                            .map_err(|e| RPCError::internal_error(format!("{}", e)))
+                    })
+                    .await
+                    .map(Some)
             }
 
             _ => Err(RPCError::unknown_method()),
@@ -79,6 +84,8 @@ async fn main() -> Result<()> {
     }
     env_logger::init();
     let addr: SocketAddr = "127.0.0.1:8080".parse()?;
-    let handle = ExampleHandle;
+    let handle = ExampleHandle {
+        cache: Cache::new(1024, Duration::from_secs(60)),
+    };
     serve(&addr, handle).await
 }
@@ -0,0 +1,181 @@
+use std::{collections::HashMap, fmt::Debug, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
+
+use crate::{RPCError, RPCRequest, RPCResult, RpcClient, RpcId};
+
+struct Queued {
+    method: String,
+    params: Value,
+    reply: oneshot::Sender<RPCResult<Option<Value>>>,
+}
+
+/// Coalesces calls made within `window` of each other into one batch request.
+///
+/// A batch is sent once `window` has passed since its first call or it holds
+/// `max_batch` calls, whichever comes first. Each call resolves with the response
+/// carrying its id. Clones share the same queue.
+#[derive(Clone)]
+pub struct AutoBatcher {
+    tx: mpsc::UnboundedSender<Queued>,
+}
+
+impl AutoBatcher {
+    pub fn new(client: RpcClient, window: Duration, max_batch: usize) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(collect(client, rx, window, max_batch.max(1)));
+        Self { tx }
+    }
+
+    pub async fn call<P, R>(&self, method: &str, params: &P) -> RPCResult<Option<R>>
+    where
+        R: for<'de> Deserialize<'de> + Debug,
+        P: Serialize,
+    {
+        let params =
+            serde_json::to_value(params).map_err(|e| RPCError::internal_error(format!("{e:?}")))?;
+        let (reply, rx) = oneshot::channel();
+        self.tx
+            .send(Queued {
+                method: String::from(method),
+                params,
+                reply,
+            })
+            .map_err(|_| RPCError::internal_error(String::from("Batcher stopped")))?;
+
+        let result = rx
+            .await
+            .map_err(|_| RPCError::internal_error(String::from("Batcher stopped")))??;
+        result
+            .map(serde_json::from_value)
+            .transpose()
+            .map_err(|e| RPCError::internal_error(format!("{e:?}")))
+    }
+}
+
+async fn collect(
+    client: RpcClient,
+    mut rx: mpsc::UnboundedReceiver<Queued>,
+    window: Duration,
+    max_batch: usize,
+) {
+    let mut next_id = 0i64;
+    while let Some(first) = rx.recv().await {
+        let deadline = Instant::now() + window;
+        let mut batch = vec![first];
+        while batch.len() < max_batch {
+            match tokio::time::timeout_at(deadline, rx.recv()).await {
+                Ok(Some(q)) => batch.push(q),
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        let mut requests = Vec::with_capacity(batch.len());
+        let mut waiting = HashMap::with_capacity(batch.len());
+        for q in batch {
+            next_id += 1;
+            let id = RpcId::Number(next_id);
+            requests.push(RPCRequest {
+                id: Some(id.clone()),
+                ..RPCRequest::new(&q.method, q.params)
+            });
+            waiting.insert(id, q.reply);
+        }
+        tokio::spawn(flush(client.clone(), requests, waiting));
+    }
+}
+
+async fn flush(
+    client: RpcClient,
+    requests: Vec<RPCRequest<Value>>,
+    mut waiting: HashMap<RpcId, oneshot::Sender<RPCResult<Option<Value>>>>,
+) {
+    log::debug!("Sending {} coalesced calls as one batch", requests.len());
+    let responses = match client.batch_call::<Value, Value>(&requests).await {
+        Ok(r) => r,
+        Err(e) => {
+            for (_, reply) in waiting {
+                let _ = reply.send(Err(RPCError::internal_error(format!("{e:?}"))));
+            }
+            return;
+        }
+    };
+
+    for resp in responses {
+        if let Some(reply) = waiting.remove(&resp.id) {
+            let _ = reply.send(resp.into_result());
+        }
+    }
+    for (id, reply) in waiting {
+        let _ = reply.send(Err(RPCError::internal_error(format!(
+            "No response for request id {}",
+            id
+        ))));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::{testing, ServerConfig};
+
+    /// A batcher calling a [`testing::serve`] server, which counts the HTTP
+    /// requests it gets.
+    async fn batcher(window: Duration, max_batch: usize) -> (AutoBatcher, Arc<AtomicUsize>) {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let addr = testing::serve(ServerConfig {
+            middleware: vec![Arc::new(move |_| {
+                counted.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            })],
+            ..Default::default()
+        })
+        .await;
+        let client = RpcClient::new(&format!("http://{addr}")).unwrap();
+        (AutoBatcher::new(client, window, max_batch), hits)
+    }
+
+    #[tokio::test]
+    async fn calls_within_the_window_go_out_as_one_batch() {
+        let (batcher, hits) = batcher(Duration::from_millis(50), 10).await;
+        let (a, b, c) = tokio::join!(
+            batcher.call::<_, Value>("echo", &["a"]),
+            batcher.call::<_, Value>("echo", &["b"]),
+            batcher.call::<_, Value>("fail", &["c"]),
+        );
+
+        assert_eq!(a.unwrap(), Some(Value::from(vec!["a"])));
+        assert_eq!(b.unwrap(), Some(Value::from(vec!["b"])));
+        assert_eq!(c.unwrap_err().code, RPCError::temporary_failure().code);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn full_batches_are_sent_before_the_window_ends() {
+        let (batcher, hits) = batcher(Duration::from_secs(10), 2).await;
+        let calls = async {
+            tokio::join!(
+                batcher.call::<_, Value>("echo", &[1]),
+                batcher.call::<_, Value>("echo", &[2]),
+            )
+        };
+        let (a, b) = tokio::time::timeout(Duration::from_secs(2), calls)
+            .await
+            .expect("a full batch waited for the window");
+
+        assert_eq!(a.unwrap(), Some(Value::from(vec![1])));
+        assert_eq!(b.unwrap(), Some(Value::from(vec![2])));
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+struct Entry<V> {
+    value: V,
+    expires: Instant,
+    used: u64,
+}
+
+struct Lru<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    /// Keys by the tick they were last used at, oldest first.
+    order: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+/// A size-bounded LRU cache whose entries expire `ttl` after being computed,
+/// for handlers to cache sub-results with [`Cache::get_or_compute`].
+///
+/// Clones share the same entries. Callers missing the same key at the same
+/// time each compute it; the last one to finish is kept.
+pub struct Cache<K, V> {
+    inner: Arc<Mutex<Lru<K, V>>>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl<K, V> Clone for Cache<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            capacity: self.capacity,
+            ttl: self.ttl,
+        }
+    }
+}
+
+impl<K, V> Cache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    /// Holds at most `capacity` entries, evicting the least recently used one.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Lru {
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+            })),
+            capacity: capacity.max(1),
+            ttl,
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut lru = self.inner.lock().unwrap();
+        let lru = &mut *lru;
+        let entry = lru.entries.get_mut(key)?;
+        if entry.expires <= Instant::now() {
+            lru.order.remove(&entry.used);
+            lru.entries.remove(key);
+            return None;
+        }
+        lru.order.remove(&entry.used);
+        lru.tick += 1;
+        entry.used = lru.tick;
+        lru.order.insert(lru.tick, key.clone());
+        Some(entry.value.clone())
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        let mut lru = self.inner.lock().unwrap();
+        lru.tick += 1;
+        let used = lru.tick;
+        let entry = Entry {
+            value,
+            expires: Instant::now() + self.ttl,
+            used,
+        };
+        if let Some(old) = lru.entries.insert(key.clone(), entry) {
+            lru.order.remove(&old.used);
+        }
+        lru.order.insert(used, key);
+        while lru.entries.len() > self.capacity {
+            let Some((_, oldest)) = lru.order.pop_first() else {
+                break;
+            };
+            lru.entries.remove(&oldest);
+        }
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let mut lru = self.inner.lock().unwrap();
+        let entry = lru.entries.remove(key)?;
+        lru.order.remove(&entry.used);
+        Some(entry.value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The cached value for `key`, computing and caching it on a miss.
+    pub async fn get_or_compute<F, Fut>(&self, key: K, compute: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        if let Some(v) = self.get(&key) {
+            return v;
+        }
+        let v = compute().await;
+        self.insert(key, v.clone());
+        v
+    }
+
+    /// Like [`Cache::get_or_compute`], but errors are returned without being cached.
+    pub async fn get_or_try_compute<F, Fut, E>(&self, key: K, compute: F) -> Result<V, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+    {
+        if let Some(v) = self.get(&key) {
+            return Ok(v);
+        }
+        let v = compute().await?;
+        self.insert(key, v.clone());
+        Ok(v)
+    }
+}
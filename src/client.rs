@@ -1,20 +1,30 @@
-use std::fmt::Debug;
+use std::{collections::HashMap, fmt::Debug};
 
 use anyhow::{anyhow, Result};
 use bytes::Bytes;
-use http_body_util::{BodyExt, Full};
-use hyper::{header::HeaderValue, Request, StatusCode, Uri};
+use futures::stream;
+use http_body_util::{BodyExt, Full, StreamBody};
+use hyper::{
+    body::{Body, Frame},
+    header::HeaderValue,
+    http::response,
+    HeaderMap, Request, StatusCode, Uri,
+};
 use hyper_tls::HttpsConnector;
-use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+use hyper_util::{
+    client::legacy::{connect::Connect, Client},
+    rt::TokioExecutor,
+};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-use crate::{RPCError, RPCRequest, RPCResponse, RPCResult};
+use crate::{Auth, RPCError, RPCRequest, RPCResponse, RPCResult, RpcId, TraceContext};
 
 pub async fn call<P, R>(
     url: &str,
     method: &str,
     params: &P,
-    auth: Option<&str>,
+    auth: Option<&Auth>,
 ) -> RPCResult<Option<R>>
 where
     R: for<'de> Deserialize<'de> + Debug,
@@ -22,19 +32,123 @@ where
 {
     let req = RPCRequest::new(method, params);
     let s = serde_json::to_string(&req).map_err(|e| RPCError::internal_error(format!("{e:?}")))?;
+
+    let (status_code, bytes) = http_post(url, s.as_bytes(), Some(&call_headers(auth)))
+        .await
+        .map_err(|e| RPCError::internal_error(format!("{e:?}")))?;
+
+    call_response(&req.id.clone().unwrap_or_default(), status_code, bytes)
+}
+
+/// Like [`call`], with `headers` added to (or replacing) the default ones.
+pub async fn call_with_headers<P, R>(
+    url: &str,
+    method: &str,
+    params: &P,
+    headers: &HeaderMap,
+) -> RPCResult<Option<R>>
+where
+    R: for<'de> Deserialize<'de> + Debug,
+    P: Serialize,
+{
+    let req = RPCRequest::new(method, params);
+    let s = serde_json::to_string(&req).map_err(|e| RPCError::internal_error(format!("{e:?}")))?;
+
+    let mut map =
+        header_map(&call_headers(None)).map_err(|e| RPCError::internal_error(format!("{e:?}")))?;
+    map.extend(headers.clone());
+    let (status_code, bytes) = http_post_header_map(url, s.as_bytes(), &map)
+        .await
+        .map_err(|e| RPCError::internal_error(format!("{e:?}")))?;
+
+    call_response(&req.id.clone().unwrap_or_default(), status_code, bytes)
+}
+
+/// Sends a request built by the caller, e.g. with its own id, and returns the whole
+/// response envelope. JSON-RPC errors come back inside it, only transport failures
+/// and mismatched ids are `Err`.
+pub async fn call_request<R>(
+    url: &str,
+    req: &RPCRequest<Value>,
+    auth: Option<&Auth>,
+) -> RPCResult<RPCResponse<R>>
+where
+    R: for<'de> Deserialize<'de> + Debug,
+{
+    let s = serde_json::to_string(req).map_err(|e| RPCError::internal_error(format!("{e:?}")))?;
+
+    let (status_code, bytes) = http_post(url, s.as_bytes(), Some(&call_headers(auth)))
+        .await
+        .map_err(|e| RPCError::internal_error(format!("{e:?}")))?;
+
+    response_envelope(&req.id.clone().unwrap_or_default(), status_code, bytes)
+}
+
+/// Like [`call`], but returns the HTTP status next to the whole response envelope.
+///
+/// Error statuses whose body is still a JSON-RPC response, e.g. 401 or 503 from the
+/// server itself, are `Ok` too; only unreadable bodies and mismatched ids are `Err`.
+pub async fn call_with_status<P, R>(
+    url: &str,
+    method: &str,
+    params: &P,
+    auth: Option<&Auth>,
+) -> RPCResult<(StatusCode, RPCResponse<R>)>
+where
+    R: for<'de> Deserialize<'de> + Debug,
+    P: Serialize,
+{
+    let req = RPCRequest::new(method, params);
+    let s = serde_json::to_string(&req).map_err(|e| RPCError::internal_error(format!("{e:?}")))?;
+
+    let (status_code, bytes) = http_post(url, s.as_bytes(), Some(&call_headers(auth)))
+        .await
+        .map_err(|e| RPCError::internal_error(format!("{e:?}")))?;
+
+    status_envelope(&req.id.clone().unwrap_or_default(), status_code, bytes)
+}
+
+pub(crate) fn call_headers(auth: Option<&Auth>) -> Vec<(&'static str, String)> {
     let mut headers = vec![
         ("content-type", String::from("application/json")),
         ("User-Agent", String::from("hyper-client")),
     ];
-    if let Some(t) = auth {
-        let r = format!("Bearer {}", t);
-        headers.push(("Authorization", r));
+    if let Some(a) = auth {
+        headers.push(("Authorization", a.header_value()));
+    }
+    trace_headers(&mut headers);
+    headers
+}
+
+/// Continues the trace of the request being served, if any.
+fn trace_headers(headers: &mut Vec<(&'static str, String)>) {
+    if let Some(ctx) = TraceContext::current().map(|c| c.child()) {
+        headers.push(("traceparent", ctx.traceparent()));
+        if let Some(state) = ctx.tracestate {
+            headers.push(("tracestate", state));
+        }
     }
+}
 
-    let (status_code, bytes) = http_post(url, s.as_bytes(), Some(&headers))
-        .await
-        .map_err(|e| RPCError::internal_error(format!("{e:?}")))?;
+pub(crate) fn call_response<R>(
+    id: &RpcId,
+    status_code: StatusCode,
+    bytes: Vec<u8>,
+) -> RPCResult<Option<R>>
+where
+    R: for<'de> Deserialize<'de> + Debug,
+{
+    response_envelope::<R>(id, status_code, bytes)?.into_result()
+}
 
+fn response_envelope<R>(
+    id: &RpcId,
+    status_code: StatusCode,
+    bytes: Vec<u8>,
+) -> RPCResult<RPCResponse<R>>
+where
+    R: for<'de> Deserialize<'de> + Debug,
+{
     if !status_code.is_success() {
         log::error!(
             "StatusCode:{:?}, Response is: {:?}",
@@ -54,18 +168,84 @@ where
 
     let resp: RPCResponse<R> =
         serde_json::from_slice(&bytes).map_err(|e| RPCError::internal_error(format!("{e:?}")))?;
+    check_id(id, resp)
+}
 
-    if let Some(e) = resp.error {
-        Err(e)
-    } else {
-        Ok(resp.result)
+pub(crate) fn status_envelope<R>(
+    id: &RpcId,
+    status_code: StatusCode,
+    bytes: Vec<u8>,
+) -> RPCResult<(StatusCode, RPCResponse<R>)>
+where
+    R: for<'de> Deserialize<'de> + Debug,
+{
+    log::debug!(
+        "StatusCode:{:?}, Response is: {:?}",
+        status_code,
+        String::from_utf8_lossy(&bytes)
+    );
+    let resp: RPCResponse<R> = serde_json::from_slice(&bytes)
+        .map_err(|e| RPCError::internal_error(format!("StatusCode:{:?}, {e:?}", status_code)))?;
+    Ok((status_code, check_id(id, resp)?))
+}
+
+fn check_id<R>(id: &RpcId, resp: RPCResponse<R>) -> RPCResult<RPCResponse<R>> {
+    // A null id is what servers send when they could not read ours (e.g. parse errors),
+    // so only treat it as a mismatch when it doesn't carry an error.
+    if &resp.id != id && !(resp.id.is_null() && resp.is_error()) {
+        return Err(RPCError::internal_error(format!(
+            "Response id {} does not match request id {}",
+            resp.id, id
+        )));
+    }
+    Ok(resp)
+}
+
+/// Collects batch entries whose params have different types, each serialized as added.
+#[derive(Debug, Clone, Default)]
+pub struct BatchBuilder {
+    requests: Vec<RPCRequest<Value>>,
+}
+
+impl BatchBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a call and returns the id its response will carry, unique within the batch.
+    pub fn add<P: Serialize>(&mut self, method: &str, params: &P) -> Result<RpcId> {
+        let id = RpcId::Number(self.requests.len() as i64 + 1);
+        self.requests.push(RPCRequest {
+            id: Some(id.clone()),
+            ..RPCRequest::new(method, serde_json::to_value(params)?)
+        });
+        Ok(id)
+    }
+
+    /// Adds a notification, which gets no response.
+    pub fn notify<P: Serialize>(&mut self, method: &str, params: &P) -> Result<()> {
+        let params = serde_json::to_value(params)?;
+        self.requests.push(RPCRequest::notification(method, params));
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    pub fn build(self) -> Vec<RPCRequest<Value>> {
+        self.requests
     }
 }
 
 pub async fn batch_call<P, R>(
     url: &str,
     requests: &Vec<RPCRequest<P>>,
-    auth: Option<&str>,
+    auth: Option<&Auth>,
 ) -> Result<Vec<RPCResponse<R>>>
 where
     R: for<'de> Deserialize<'de>,
@@ -73,13 +253,72 @@ where
 {
     let s = serde_json::to_string(&requests)?;
 
+    let (status_code, bytes) = http_post(url, s.as_bytes(), Some(&batch_headers(auth))).await?;
+    batch_response(status_code, bytes)
+}
+
+/// Like [`batch_call`], failing unless the responses carry exactly the `expected` ids,
+/// see [`verify_batch_ids`].
+pub async fn batch_call_expecting<P, R>(
+    url: &str,
+    requests: &Vec<RPCRequest<P>>,
+    expected: &[RpcId],
+    auth: Option<&Auth>,
+) -> Result<Vec<RPCResponse<R>>>
+where
+    R: for<'de> Deserialize<'de>,
+    P: Serialize + Clone,
+{
+    let responses = batch_call(url, requests, auth).await?;
+    verify_batch_ids(expected, &responses)?;
+    Ok(responses)
+}
+
+/// Like [`batch_call`], but serializes the requests lazily into a streamed
+/// body instead of building the whole batch in memory first.
+pub async fn batch_call_stream<P, R, I>(
+    url: &str,
+    requests: I,
+    auth: Option<&Auth>,
+) -> Result<Vec<RPCResponse<R>>>
+where
+    R: for<'de> Deserialize<'de>,
+    P: Serialize + Clone,
+    I: IntoIterator<Item = RPCRequest<P>>,
+    I::IntoIter: Send + 'static,
+{
+    let entries = requests.into_iter().enumerate().map(|(i, req)| {
+        let mut buf = if i == 0 { vec![] } else { vec![b','] };
+        serde_json::to_writer(&mut buf, &req)?;
+        Ok::<_, serde_json::Error>(Frame::data(Bytes::from(buf)))
+    });
+    let frames = std::iter::once(Ok(Frame::data(Bytes::from_static(b"["))))
+        .chain(entries)
+        .chain(std::iter::once(Ok(Frame::data(Bytes::from_static(b"]")))));
+    let body = StreamBody::new(stream::iter(frames)).boxed_unsync();
+
+    let uri: Uri = url.parse()?;
+    let request = Request::post(uri).body(body)?;
+    let (status_code, bytes) = send_http_request(request, Some(&batch_headers(auth))).await?;
+    batch_response(status_code, bytes)
+}
+
+pub(crate) fn batch_headers(auth: Option<&Auth>) -> Vec<(&'static str, String)> {
     let mut headers = vec![("content-type", String::from("application/json"))];
-    if let Some(t) = auth {
-        let r = format!("Bearer {}", t);
-        headers.push(("Authorization", r));
+    if let Some(a) = auth {
+        headers.push(("Authorization", a.header_value()));
     }
+    trace_headers(&mut headers);
+    headers
+}
 
-    let (status_code, bytes) = http_post(url, s.as_bytes(), Some(&headers)).await?;
+pub(crate) fn batch_response<R>(
+    status_code: StatusCode,
+    bytes: Vec<u8>,
+) -> Result<Vec<RPCResponse<R>>>
+where
+    R: for<'de> Deserialize<'de>,
+{
     log::debug!(
         "StatusCode:{:?}, Response is: {:?}",
         status_code,
@@ -87,6 +326,10 @@ where
     );
 
     if status_code.is_success() {
+        // A batch of notifications only is answered with nothing at all.
+        if bytes.is_empty() {
+            return Ok(vec![]);
+        }
         Ok(serde_json::from_slice(&bytes)?)
     } else {
         log::error!(
@@ -98,6 +341,53 @@ where
     }
 }
 
+/// Checks that `responses` answer every call in `requests` exactly once and nothing
+/// else, listing the ids missing and the ids unexpected, e.g. duplicated, if not.
+pub fn verify_batch_complete<P: Clone, R>(
+    requests: &[RPCRequest<P>],
+    responses: &[RPCResponse<R>],
+) -> Result<()> {
+    verify_batch_ids(requests.iter().filter_map(|r| r.id.as_ref()), responses)
+}
+
+/// Like [`verify_batch_complete`], against the ids `expected` to be answered, each
+/// once per time it is listed.
+pub fn verify_batch_ids<'a, R>(
+    expected: impl IntoIterator<Item = &'a RpcId>,
+    responses: &[RPCResponse<R>],
+) -> Result<()> {
+    let expected: Vec<&RpcId> = expected.into_iter().collect();
+    let mut remaining: HashMap<&RpcId, usize> = HashMap::new();
+    for id in &expected {
+        *remaining.entry(id).or_default() += 1;
+    }
+    let mut unexpected = vec![];
+    for resp in responses {
+        match remaining.get_mut(&resp.id) {
+            Some(n) if *n > 0 => *n -= 1,
+            _ => unexpected.push(resp.id.to_string()),
+        }
+    }
+    let mut missing = vec![];
+    for id in expected {
+        match remaining.get_mut(id) {
+            Some(n) if *n > 0 => {
+                *n -= 1;
+                missing.push(id.to_string());
+            }
+            _ => {}
+        }
+    }
+    if missing.is_empty() && unexpected.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "Incomplete batch response, missing ids [{}], unexpected ids [{}]",
+        missing.join(", "),
+        unexpected.join(", ")
+    ))
+}
+
 pub async fn http_post_ret_string(
     url: &str,
     body: &[u8],
@@ -114,9 +404,23 @@ pub async fn http_post(
     headers: Option<&[(&'static str, String)]>,
 ) -> Result<(StatusCode, Vec<u8>)> {
     let uri: Uri = url.parse()?;
-    let request = Request::post(uri).body(Full::from(body.to_vec()))?;
+    let request = Request::post(uri).body(Full::<Bytes>::from(body.to_vec()))?;
     send_http_request(request, headers).await
 }
+
+/// Like [`http_post`], but takes a [`HeaderMap`], e.g. for multi-valued or
+/// non-static header names. Its headers replace any defaults of the same name.
+pub async fn http_post_header_map(
+    url: &str,
+    body: &[u8],
+    headers: &HeaderMap,
+) -> Result<(StatusCode, Vec<u8>)> {
+    let uri: Uri = url.parse()?;
+    let mut request = Request::post(uri).body(Full::<Bytes>::from(body.to_vec()))?;
+    request.headers_mut().extend(headers.clone());
+    send_http_request(request, None).await
+}
+
 pub async fn http_get_ret_string(
     url: &str,
     body: &[u8],
@@ -133,26 +437,328 @@ pub async fn http_get(
     headers: Option<&[(&'static str, String)]>,
 ) -> Result<(StatusCode, Vec<u8>)> {
     let uri: Uri = url.parse()?;
-    let request = Request::get(uri).body(Full::from(body.to_vec()))?;
+    let request = Request::get(uri).body(Full::<Bytes>::from(body.to_vec()))?;
     send_http_request(request, headers).await
 }
 
-async fn send_http_request(
-    mut request: Request<Full<Bytes>>,
+async fn send_http_request<B>(
+    request: Request<B>,
     headers: Option<&[(&'static str, String)]>,
-) -> Result<(StatusCode, Vec<u8>)> {
+) -> Result<(StatusCode, Vec<u8>)>
+where
+    B: Body + Send + Unpin + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
     let connector = HttpsConnector::new();
     let client = Client::builder(TokioExecutor::new()).build(connector);
+    send_request(&client, request, headers).await
+}
 
+pub(crate) async fn send_request<C, B>(
+    client: &Client<C, B>,
+    request: Request<B>,
+    headers: Option<&[(&'static str, String)]>,
+) -> Result<(StatusCode, Vec<u8>)>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+    B: Body + Send + Unpin + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let (parts, body) = send_request_parts(client, request, headers).await?;
+    Ok((parts.status, body))
+}
+
+pub(crate) fn header_map(headers: &[(&'static str, String)]) -> Result<HeaderMap> {
+    let mut map = HeaderMap::with_capacity(headers.len());
+    for (h, v) in headers {
+        map.insert(*h, HeaderValue::from_str(v)?);
+    }
+    Ok(map)
+}
+
+pub(crate) async fn send_request_parts<C, B>(
+    client: &Client<C, B>,
+    mut request: Request<B>,
+    headers: Option<&[(&'static str, String)]>,
+) -> Result<(response::Parts, Vec<u8>)>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+    B: Body + Send + Unpin + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
     if let Some(v) = headers {
-        let hs = request.headers_mut();
-        for (h, v) in v.iter() {
-            hs.insert(*h, HeaderValue::from_str(v)?);
+        request.headers_mut().extend(header_map(v)?);
+    }
+
+    let (parts, body) = client.request(request).await?.into_parts();
+    let body = body.collect().await?.to_bytes().to_vec();
+    Ok((parts, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::Value;
+
+    use super::*;
+    use crate::testing;
+
+    #[tokio::test]
+    async fn rejects_a_response_to_another_id() {
+        let body = r#"{"jsonrpc": "2.0", "result": 1, "id": "someone else's"}"#;
+        let (url, _) = testing::respond_with(StatusCode::OK, body).await;
+        let e = call::<_, Value>(&url, "m", &(), None).await.unwrap_err();
+        assert!(e.data.unwrap().contains("does not match"));
+
+        let addr = testing::serve(Default::default()).await;
+        let r: Option<Value> = call(&format!("http://{addr}"), "echo", &[1], None)
+            .await
+            .unwrap();
+        assert_eq!(r, Some(serde_json::json!([1])));
+    }
+
+    fn answered(ids: &[i64]) -> Vec<RPCResponse<Value>> {
+        ids.iter()
+            .map(|&id| RPCResponse::from_result(RpcId::from(id), Ok(None)))
+            .collect()
+    }
+
+    #[test]
+    fn batches_answer_every_call_once() {
+        let call = |id: i64| RPCRequest {
+            id: Some(RpcId::from(id)),
+            ..RPCRequest::new("m", ())
+        };
+        let requests = [call(1), call(2), RPCRequest::notification("m", ())];
+        assert!(verify_batch_complete(&requests, &answered(&[2, 1])).is_ok());
+
+        let e = verify_batch_complete(&requests, &answered(&[1])).unwrap_err();
+        assert!(e.to_string().contains("missing ids [2], unexpected ids []"));
+        let e = verify_batch_complete(&requests, &answered(&[1, 2, 2, 3])).unwrap_err();
+        assert!(e
+            .to_string()
+            .contains("missing ids [], unexpected ids [2, 3]"));
+    }
+
+    #[tokio::test]
+    async fn batch_call_expecting_checks_the_ids_answered() {
+        let url = format!("http://{}", testing::serve(Default::default()).await);
+        // Notifications take no id and get no response, so only the calls are expected.
+        let mut batch = BatchBuilder::new();
+        batch.notify("echo", &[0]).unwrap();
+        let first = batch.add("echo", &[1]).unwrap();
+        batch.notify("fail", &[0]).unwrap();
+        let second = batch.add("echo", &[2]).unwrap();
+        let requests = batch.build();
+
+        let expected = [first.clone(), second.clone()];
+        let responses = batch_call_expecting::<_, Value>(&url, &requests, &expected, None)
+            .await
+            .unwrap();
+        assert_eq!(responses.len(), 2);
+        let extra = [first.clone(), second.clone(), RpcId::from(5)];
+        let e = batch_call_expecting::<_, Value>(&url, &requests, &extra, None)
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("missing ids [5], unexpected ids []"));
+        let e = batch_call_expecting::<_, Value>(&url, &requests, &[first], None)
+            .await
+            .unwrap_err();
+        let unexpected = format!("missing ids [], unexpected ids [{second}]");
+        assert!(e.to_string().contains(&unexpected), "{e}");
+    }
+
+    #[tokio::test]
+    async fn call_request_sends_the_request_as_built() {
+        let url = format!("http://{}", testing::serve(Default::default()).await);
+        let req = RPCRequest {
+            id: Some(RpcId::from("req-7f3a")),
+            ..RPCRequest::new("echo", serde_json::json!(["x"]))
+        };
+        let resp = call_request::<Value>(&url, &req, None).await.unwrap();
+        assert_eq!(resp.id, RpcId::from("req-7f3a"));
+        assert_eq!(resp.as_result(), Some(&serde_json::json!(["x"])));
+
+        // JSON-RPC errors come back inside the envelope.
+        let req = RPCRequest {
+            id: Some(RpcId::from("req-7f3b")),
+            ..RPCRequest::new("nope", Value::Null)
+        };
+        let resp = call_request::<Value>(&url, &req, None).await.unwrap();
+        assert_eq!(resp.id, RpcId::from("req-7f3b"));
+        assert_eq!(resp.as_error().unwrap().code, -32601);
+    }
+
+    #[test]
+    fn notifications_are_serialized_without_an_id() {
+        let mut batch = BatchBuilder::new();
+        batch.add("m", &[1]).unwrap();
+        batch.notify("n", &[2]).unwrap();
+        let entries: Value = serde_json::to_value(batch.build()).unwrap();
+        assert_eq!(entries[0]["id"], 1);
+        assert!(entries[1].get("id").is_none(), "{entries}");
+        let null = RPCRequest {
+            id: Some(RpcId::Null),
+            ..RPCRequest::new("m", ())
+        };
+        assert_eq!(serde_json::to_value(null).unwrap()["id"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn batches_of_notifications_only_get_no_responses() {
+        let url = format!("http://{}", testing::serve(Default::default()).await);
+        let mut batch = BatchBuilder::new();
+        batch.notify("echo", &[1]).unwrap();
+        batch.notify("nope", &[2]).unwrap();
+        let responses = batch_call::<_, Value>(&url, &batch.build(), None)
+            .await
+            .unwrap();
+        assert!(responses.is_empty());
+    }
+
+    #[tokio::test]
+    async fn header_maps_reach_the_server() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let saw = seen.clone();
+        let addr = testing::serve(crate::ServerConfig {
+            middleware: vec![Arc::new(move |ctx| {
+                let tenants = ctx.headers.get_all("x-tenant").iter();
+                saw.lock()
+                    .unwrap()
+                    .extend(tenants.map(|v| v.to_str().unwrap().to_string()));
+                Ok(())
+            })],
+            ..Default::default()
+        })
+        .await;
+
+        let mut headers = HeaderMap::new();
+        headers.append("x-tenant", HeaderValue::from_static("a"));
+        headers.append("x-tenant", HeaderValue::from_static("b"));
+        let body = serde_json::to_vec(&RPCRequest::new("echo", [1])).unwrap();
+        let (status, body) = http_post_header_map(&format!("http://{addr}"), &body, &headers)
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        let resp: RPCResponse<Value> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(resp.into_result().unwrap(), Some(serde_json::json!([1])));
+        assert_eq!(*seen.lock().unwrap(), ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn batch_builders_mix_params_of_any_type() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Named {
+            name: String,
+        }
+
+        let mut batch = BatchBuilder::new();
+        let pair = batch.add("echo", &(1u32, "a")).unwrap();
+        let named = Named {
+            name: String::from("b"),
+        };
+        let object = batch.add("echo", &named).unwrap();
+        batch.notify("echo", &[true]).unwrap();
+        let floats = batch.add("echo", &[1.5]).unwrap();
+        assert_eq!(batch.len(), 4);
+
+        let addr = testing::serve(Default::default()).await;
+        let expected = [pair.clone(), object.clone(), floats.clone()];
+        let responses: Vec<RPCResponse<Value>> =
+            batch_call_expecting(&format!("http://{addr}"), &batch.build(), &expected, None)
+                .await
+                .unwrap();
+        let mut results: HashMap<_, _> = responses
+            .into_iter()
+            .map(|r| (r.id.clone(), r.try_into_result().unwrap()))
+            .collect();
+        fn typed<T: for<'de> Deserialize<'de>>(v: Value) -> T {
+            serde_json::from_value(v).unwrap()
         }
+
+        let (n, s): (u32, String) = typed(results.remove(&pair).unwrap());
+        assert_eq!((n, s.as_str()), (1, "a"));
+        let o: Named = typed(results.remove(&object).unwrap());
+        assert_eq!(o, named);
+        let f: Vec<f64> = typed(results.remove(&floats).unwrap());
+        assert_eq!(f, [1.5]);
     }
 
-    let response = client.request(request).await?;
-    let status_code = response.status();
-    let body = response.into_body().collect().await?.to_bytes().to_vec();
-    Ok((status_code, body))
+    #[tokio::test]
+    async fn call_with_status_returns_the_http_status() {
+        let addr = testing::serve(crate::ServerConfig {
+            auth: vec![Auth::bearer("token")],
+            ..Default::default()
+        })
+        .await;
+        let url = format!("http://{addr}");
+        let token = Auth::bearer("token");
+
+        let (status, resp) = call_with_status::<_, Value>(&url, "echo", &[1], Some(&token))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(resp.into_result().unwrap(), Some(serde_json::json!([1])));
+
+        let (status, resp) = call_with_status::<_, Value>(&url, "fail", &[1], Some(&token))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        let e = resp.into_result().unwrap_err();
+        assert_eq!(e.code, RPCError::temporary_failure().code);
+
+        let (status, resp) = call_with_status::<_, Value>(&url, "echo", &[1], None)
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(resp.is_error());
+
+        let busy = r#"{"jsonrpc":"2.0","error":{"code":-32000,"message":"Server busy"},"id":null}"#;
+        let (url, _) = testing::respond_with(StatusCode::SERVICE_UNAVAILABLE, busy).await;
+        let (status, resp) = call_with_status::<_, Value>(&url, "echo", &[1], None)
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.into_result().unwrap_err().code, -32000);
+    }
+
+    #[tokio::test]
+    async fn mixed_batches_are_answered_for_their_calls_only() {
+        let call = |id: RpcId, method: &str| RPCRequest {
+            id: Some(id),
+            ..RPCRequest::new(method, serde_json::json!([5]))
+        };
+        let requests = vec![
+            call(RpcId::from(1), "echo"),
+            RPCRequest::notification("echo", serde_json::json!([5])),
+            call(RpcId::from("two"), "fail"),
+            RPCRequest::notification("no such method", serde_json::json!([5])),
+            call(RpcId::from(3), "sleep"),
+        ];
+
+        // Streamed batches skip notifications on a path of their own.
+        for stream_batches in [false, true] {
+            let addr = testing::serve(crate::ServerConfig {
+                stream_batches,
+                ..Default::default()
+            })
+            .await;
+            let url = format!("http://{addr}");
+            let responses = crate::RpcClient::new(&url)
+                .unwrap()
+                .batch_call::<_, Value>(&requests)
+                .await
+                .unwrap();
+            verify_batch_complete(&requests, &responses).unwrap();
+
+            let mut more = requests.clone();
+            more.push(call(RpcId::from(4), "echo"));
+            let e = verify_batch_complete(&more, &responses).unwrap_err();
+            assert!(e.to_string().contains("missing ids [4], unexpected ids []"));
+        }
+    }
 }
@@ -0,0 +1,109 @@
+use anyhow::Result;
+use serde_json::Value;
+
+pub(crate) const MSGPACK: &str = "application/msgpack";
+
+/// A wire format for HTTP request and response bodies, see [`ServerConfig::codecs`](crate::ServerConfig::codecs).
+pub trait Codec: Send + Sync {
+    /// The media type this codec reads and writes, e.g. `application/cbor`.
+    fn content_type(&self) -> &str;
+
+    fn decode_request(&self, body: &[u8]) -> Result<Value>;
+
+    fn encode_response(&self, value: &Value) -> Result<Vec<u8>>;
+}
+
+/// Plain JSON, which the server speaks without being configured to.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn content_type(&self) -> &str {
+        "application/json"
+    }
+
+    fn decode_request(&self, body: &[u8]) -> Result<Value> {
+        Ok(serde_json::from_slice(body)?)
+    }
+
+    fn encode_response(&self, value: &Value) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(value)?)
+    }
+}
+
+/// MessagePack, with structs encoded as maps.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MsgPackCodec;
+
+impl Codec for MsgPackCodec {
+    fn content_type(&self) -> &str {
+        MSGPACK
+    }
+
+    fn decode_request(&self, body: &[u8]) -> Result<Value> {
+        Ok(rmp_serde::from_slice(body)?)
+    }
+
+    fn encode_response(&self, value: &Value) -> Result<Vec<u8>> {
+        Ok(rmp_serde::to_vec_named(value)?)
+    }
+}
+
+/// Whether `header`, a `Content-Type` or one entry of an `Accept` list, names `media`.
+pub(crate) fn is_media(header: &str, media: &str) -> bool {
+    let essence = header.split(';').next().unwrap_or_default();
+    essence.trim().eq_ignore_ascii_case(media)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hyper::StatusCode;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{http_post, testing, ServerConfig};
+
+    /// JSON written back to front.
+    struct Backwards;
+
+    impl Codec for Backwards {
+        fn content_type(&self) -> &str {
+            "application/x-backwards"
+        }
+
+        fn decode_request(&self, body: &[u8]) -> Result<Value> {
+            let body: Vec<u8> = body.iter().rev().copied().collect();
+            Ok(serde_json::from_slice(&body)?)
+        }
+
+        fn encode_response(&self, value: &Value) -> Result<Vec<u8>> {
+            let mut body = serde_json::to_vec(value)?;
+            body.reverse();
+            Ok(body)
+        }
+    }
+
+    #[tokio::test]
+    async fn calls_round_trip_through_a_custom_codec() {
+        let addr = testing::serve(ServerConfig {
+            codecs: vec![Arc::new(Backwards)],
+            ..Default::default()
+        })
+        .await;
+        let request = json!({"jsonrpc": "2.0", "id": 1, "method": "echo", "params": ["ab"]});
+        let body = Backwards.encode_response(&request).unwrap();
+        let headers = [
+            ("Content-Type", String::from("application/x-backwards")),
+            ("Accept", String::from("application/x-backwards")),
+        ];
+
+        let (status, body) = http_post(&format!("http://{addr}"), &body, Some(&headers))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        let resp = Backwards.decode_request(&body).unwrap();
+        assert_eq!(resp, json!({"jsonrpc": "2.0", "result": ["ab"], "id": 1}));
+    }
+}
@@ -0,0 +1,540 @@
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    net::IpAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use hyper::{
+    body::Incoming,
+    header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE},
+    server::conn::http1,
+    HeaderMap, Request, Response,
+};
+use hyper_util::rt::TokioTimer;
+pub use ipnet::IpNet;
+use serde_json::Value;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{
+    codec, json, Auth, Authorizer, Broadcaster, CallLatencies, Codec, Middleware, MsgPackCodec,
+    RPCError, RPCResult, RequestSigner, ResponseBody, RetryPolicy, ServerEvent, ServerEvents,
+};
+
+/// The smallest read buffer hyper accepts for an HTTP/1 connection.
+const MIN_HEADER_SIZE: usize = 8192;
+
+/// Rewrites a request or response body before it is logged.
+pub type Redactor = Arc<dyn Fn(&Value) -> Value + Send + Sync>;
+
+/// Rewrites a raw request or response envelope, see [`ServerConfig::request_transform`].
+pub type Transform = Arc<dyn Fn(Value) -> Value + Send + Sync>;
+
+/// Runs the task serving one connection, see [`ServerConfig::spawner`].
+pub type Spawner = Arc<dyn Fn(BoxFuture<'static, ()>) + Send + Sync>;
+
+/// Caps how many batches one server processes at a time; clones share the same slots.
+#[derive(Debug, Clone)]
+pub struct BatchLimit(Arc<Semaphore>);
+
+impl BatchLimit {
+    pub fn new(max: usize) -> Self {
+        Self(Arc::new(Semaphore::new(max)))
+    }
+}
+
+/// Builds the response for requests outside [`ServerConfig::rpc_path`].
+pub type NotFoundHandler = Arc<dyn Fn(&Request<Incoming>) -> Response<ResponseBody> + Send + Sync>;
+
+#[derive(Clone)]
+pub struct ServerConfig {
+    /// Log full request and response bodies at debug level. Off by default.
+    pub log_bodies: bool,
+    /// Level of the line logged for every call, info by default. Calls to unknown
+    /// methods are logged at warn as well, with the caller's address.
+    pub call_log_level: log::Level,
+    /// Applied to bodies before they are logged, see [`redact_paths`].
+    pub redactor: Option<Redactor>,
+    /// Requests in flight above this mark are answered straight away with
+    /// `server_busy` and HTTP 503 instead of being queued. Calls running over
+    /// WebSocket count too and are refused with `server_busy` alone. Unlimited by default.
+    pub max_in_flight: Option<usize>,
+    /// `Content-Type` of every response, `application/json` by default.
+    pub content_type: String,
+    /// Accept WebSocket upgrades, which also enables [`Handle::subscribe`](crate::Handle::subscribe).
+    ///
+    /// Calls on one connection run concurrently and are answered as they finish; a
+    /// client aborts one of its own with `rpc.cancel` and the request's id, which
+    /// answers `true` if it was still running and `-32800` to the cancelled call.
+    pub websocket: bool,
+    /// Ping WebSocket clients idle for this long, closing the connection if nothing,
+    /// pong or otherwise, comes back within [`ServerConfig::ws_pong_timeout`]. Off by default.
+    pub ws_ping_interval: Option<Duration>,
+    /// How long a pinged WebSocket client has to answer, 20 seconds by default.
+    pub ws_pong_timeout: Duration,
+    /// When non-empty, only peers inside one of these networks may connect.
+    pub allow: Vec<IpNet>,
+    /// Peers inside any of these networks are disconnected, even if allowed.
+    pub deny: Vec<IpNet>,
+    /// Credentials accepted in the `Authorization` header. Empty disables authentication.
+    pub auth: Vec<Auth>,
+    /// Run in order on each HTTP request, see [`Middleware`].
+    pub middleware: Vec<Middleware>,
+    /// Asked about every call once authenticated, over HTTP and WebSocket alike.
+    pub authorizer: Option<Arc<dyn Authorizer>>,
+    /// Per-method JSON Schemas that `params` must satisfy, see [`ServerConfig::param_schema`].
+    pub param_schemas: HashMap<String, Arc<jsonschema::Validator>>,
+    /// Keep a clone to push notifications to WebSocket clients listening on them.
+    pub broadcaster: Broadcaster,
+    /// Streams [`ServerEvent`]s to WebSocket clients calling `rpc.events`, optionally
+    /// with a list of event types to receive. Each subscription needs
+    /// [`ServerConfig::authorizer`] to allow method `rpc.events`; without an authorizer
+    /// it is always forbidden. `None`, the default, emits no events.
+    pub events: Option<ServerEvents>,
+    /// Calls whose handler takes longer emit [`ServerEvent::Slow`]. `None` by default.
+    pub slow_call: Option<Duration>,
+    /// Records how long each call's handler takes, per method. Keep a clone, or use
+    /// [`ServerStats::latency_percentile`](crate::ServerStats::latency_percentile), to
+    /// read percentiles. Entries of a batch are timed like single calls. Off by default.
+    pub latencies: Option<CallLatencies>,
+    /// Request bodies nesting arrays/objects deeper than this are rejected with `-32700`
+    /// before parsing. `None` leaves only serde_json's own recursion limit.
+    pub max_depth: Option<usize>,
+    /// Answer batches as `application/x-ndjson`, one response per line in completion
+    /// order rather than request order, written as soon as each handler resolves.
+    /// Entries go through [`Handle::handle`](crate::Handle::handle) one by one, up to
+    /// [`Handle::BATCH_CONCURRENCY`](crate::Handle::BATCH_CONCURRENCY) at a time, like
+    /// the entries of any batch.
+    pub stream_batches: bool,
+    /// Gzip [`ServerConfig::stream_batches`] responses on the fly for clients sending
+    /// `Accept-Encoding: gzip`. Off by default.
+    pub gzip_streamed_batches: bool,
+    /// Larger request bodies get HTTP 413, or 417 when announced with
+    /// `Expect: 100-continue`. Unlimited by default.
+    pub max_body_size: Option<usize>,
+    /// Per-method retries of transient handler errors, e.g. `RPCError::temporary_failure`,
+    /// backing off between attempts like a client does.
+    pub handler_retries: HashMap<String, RetryPolicy>,
+    /// Encode responses as MessagePack for clients sending `Accept: application/msgpack`.
+    pub msgpack: bool,
+    /// Formats besides JSON, decoding HTTP requests whose `Content-Type` names one and
+    /// encoding responses in the first one the client's `Accept` lists. Streamed batches
+    /// and errors rejecting the request itself are always JSON.
+    pub codecs: Vec<Arc<dyn Codec>>,
+    /// Path answered with `200 {"status":"ok"}` before auth, load shedding or maintenance,
+    /// and with `503 {"status":"draining"}` once the server drains.
+    pub health_path: Option<String>,
+    /// Keep accepting connections this long after the shutdown signal, answering health
+    /// checks not ready and new calls 503 `server_busy`, so load balancers stop routing
+    /// here before the listeners close. `None`, the default, closes them right away.
+    pub drain_period: Option<Duration>,
+    /// Cap on the buffered request line plus headers; clients exceeding it get HTTP 431.
+    /// hyper's default is about 400 KiB, and values below 8 KiB are raised to 8 KiB.
+    pub max_header_size: Option<usize>,
+    /// Maximum number of request headers, 100 by default.
+    pub max_headers: Option<usize>,
+    /// The only path JSON-RPC (and WebSocket upgrades) are served on. `None` serves every path.
+    /// Other requests than POSTs and upgrades get `405` with `Allow: POST` there.
+    pub rpc_path: Option<String>,
+    /// Answers requests outside `rpc_path`; an empty 404 by default.
+    pub not_found_handler: Option<NotFoundHandler>,
+    /// Close connections that go this long without starting the next request, or that
+    /// take longer to send its headers. Connections may idle forever by default.
+    pub keepalive_timeout: Option<Duration>,
+    /// Sort the keys of every object inside results, see [`RPCResponse::canonical`](crate::RPCResponse::canonical).
+    /// Useful for hashing responses; costs an extra conversion per response.
+    pub canonical_json: bool,
+    /// Applied over HTTP to every request body (single or batch) right after it is
+    /// parsed as JSON, before any validation, e.g. to bridge a legacy protocol.
+    pub request_transform: Option<Transform>,
+    /// Applied over HTTP to every response body before it is encoded and sent,
+    /// except to [`ServerConfig::stream_batches`] responses.
+    pub response_transform: Option<Transform>,
+    /// Require HTTP request bodies to carry a valid signature, answering 401 otherwise.
+    pub signer: Option<RequestSigner>,
+    /// Methods answered with a canned result or error without calling the handler,
+    /// e.g. to switch off an expensive method or stub it during a migration. The
+    /// call is still checked and authorized first, only the handler is skipped.
+    pub method_overrides: HashMap<String, RPCResult<Value>>,
+    /// Drop the handler of a request whose client closes the connection before the
+    /// response is written. On by default; off lets handlers run to completion.
+    pub cancel_on_disconnect: bool,
+    /// Batches arriving while all slots are taken are answered with `server_busy`
+    /// without running any entry. Unlimited by default.
+    pub max_concurrent_batches: Option<BatchLimit>,
+    /// Answer `-32600` to requests with members other than `jsonrpc`, `method`,
+    /// `params` and `id`, which are ignored by default.
+    pub strict_members: bool,
+    /// Answer `-32600` to request bodies repeating a member within one request, e.g.
+    /// two `method`s, of which serde_json would silently take the last. Off by default.
+    pub reject_duplicate_keys: bool,
+    /// Answer `-32600` to requests whose id is a number other than an integer, e.g.
+    /// `1.5`, which the spec discourages. Off by default, echoing such ids back.
+    pub reject_fractional_ids: bool,
+    /// Pipelined requests on one connection are always answered in request order;
+    /// this also coalesces their responses into fewer writes. Off by default.
+    pub pipeline_flush: bool,
+    /// Methods still served but logged as deprecated, each with an optional
+    /// replacement to point callers to. HTTP responses to them carry a `Warning` header.
+    pub deprecated: HashMap<String, Option<String>>,
+    /// Write `"data": null` into error objects without data, for clients that expect
+    /// the member to always be there. Off by default, omitting it as the spec allows.
+    pub always_error_data: bool,
+    /// Answer calls whose handler takes longer with `request_timeout`, dropping the
+    /// handler. Retries count towards it, and each entry of a batch has its own.
+    /// Unlimited by default.
+    pub request_timeout: Option<Duration>,
+    /// Methods that always produce a value: calls to them whose handler returns
+    /// `Ok(None)` are answered `-32603` instead of `"result": null`.
+    pub non_empty_results: HashSet<String>,
+    /// Methods taking no parameters: calls to them with params other than `null`, `[]`
+    /// or `{}` are answered `-32602` rather than having the params ignored.
+    pub no_params: HashSet<String>,
+    /// When non-empty, calls to methods starting with none of these prefixes are
+    /// answered `-32601` without reaching the handler, e.g. `["eth_", "net_"]`.
+    pub method_prefixes: Vec<String>,
+    /// `Cache-Control` directives, e.g. `max-age=60`, sent with successful responses
+    /// to single HTTP calls of these methods for downstream caches to honour.
+    pub cache_control: HashMap<String, String>,
+    /// Per-method timeouts that replace [`ServerConfig::request_timeout`].
+    pub method_timeouts: HashMap<String, Duration>,
+    /// Responses serializing to more bytes of JSON are replaced with `-32004`
+    /// "Response too large", per entry of a batch. Unlimited by default.
+    pub max_response_bytes: Option<usize>,
+    /// Responses nesting arrays/objects deeper than this, counted like
+    /// [`ServerConfig::max_depth`], are replaced with `-32603`. Unlimited by default.
+    pub max_response_depth: Option<usize>,
+    /// Spawns the task of each accepted connection and WebSocket session instead of
+    /// `tokio::spawn`, e.g. onto a scheduler of the application's own. Sockets and timers
+    /// are still tokio's, so a tokio runtime must keep running to drive them.
+    pub spawner: Option<Spawner>,
+    /// Trim results over HTTP to the fields a client lists in an `X-Result-Fields`
+    /// header as comma-separated paths, e.g. `name,owner.id`; arrays are trimmed
+    /// element by element. Not applied to streamed batches. Off by default.
+    pub result_fields: bool,
+    /// Serve a page at `GET /` for typing in calls and sending them to the endpoint.
+    /// For development only, it is served without checking [`ServerConfig::auth`].
+    pub playground: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            log_bodies: false,
+            call_log_level: log::Level::Info,
+            redactor: None,
+            max_in_flight: None,
+            content_type: String::from("application/json"),
+            websocket: false,
+            ws_ping_interval: None,
+            ws_pong_timeout: Duration::from_secs(20),
+            allow: vec![],
+            deny: vec![],
+            auth: vec![],
+            middleware: vec![],
+            authorizer: None,
+            param_schemas: HashMap::new(),
+            broadcaster: Broadcaster::default(),
+            events: None,
+            slow_call: None,
+            latencies: None,
+            max_depth: None,
+            stream_batches: false,
+            gzip_streamed_batches: false,
+            max_body_size: None,
+            handler_retries: HashMap::new(),
+            msgpack: false,
+            codecs: vec![],
+            health_path: None,
+            drain_period: None,
+            max_header_size: None,
+            max_headers: None,
+            rpc_path: None,
+            not_found_handler: None,
+            keepalive_timeout: None,
+            canonical_json: false,
+            request_transform: None,
+            response_transform: None,
+            signer: None,
+            method_overrides: HashMap::new(),
+            cancel_on_disconnect: true,
+            max_concurrent_batches: None,
+            strict_members: false,
+            reject_duplicate_keys: false,
+            reject_fractional_ids: false,
+            pipeline_flush: false,
+            deprecated: HashMap::new(),
+            always_error_data: false,
+            request_timeout: None,
+            method_timeouts: HashMap::new(),
+            cache_control: HashMap::new(),
+            method_prefixes: vec![],
+            non_empty_results: HashSet::new(),
+            no_params: HashSet::new(),
+            max_response_bytes: None,
+            max_response_depth: None,
+            spawner: None,
+            result_fields: false,
+            playground: false,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Validates `params` of `method` against `schema` before they are deserialized,
+    /// answering `-32602` with the validation errors in `data` when they don't match.
+    pub fn param_schema(mut self, method: &str, schema: &Value) -> Result<Self> {
+        let validator = jsonschema::validator_for(schema).map_err(|e| anyhow!("{e}"))?;
+        self.param_schemas
+            .insert(String::from(method), Arc::new(validator));
+        Ok(self)
+    }
+
+    pub(crate) fn http1(&self) -> http1::Builder {
+        let mut builder = http1::Builder::new();
+        // With half-closes allowed hyper stops watching for the client's EOF.
+        builder.half_close(!self.cancel_on_disconnect);
+        builder.pipeline_flush(self.pipeline_flush);
+        if let Some(max) = self.max_header_size {
+            builder.max_buf_size(max.max(MIN_HEADER_SIZE));
+        }
+        if let Some(max) = self.max_headers {
+            builder.max_headers(max);
+        }
+        if let Some(timeout) = self.keepalive_timeout {
+            builder
+                .timer(TokioTimer::new())
+                .header_read_timeout(timeout);
+        }
+        builder
+    }
+
+    pub(crate) fn timeout(&self, method: &str) -> Option<Duration> {
+        self.method_timeouts
+            .get(method)
+            .copied()
+            .or(self.request_timeout)
+    }
+
+    /// Emits the event `f` builds, if anyone is subscribed to events.
+    pub(crate) fn emit(&self, f: impl FnOnce() -> ServerEvent) {
+        if let Some(events) = self.events.as_ref().filter(|e| e.is_watched()) {
+            events.emit(f());
+        }
+    }
+
+    pub(crate) fn method_allowed(&self, method: &str) -> bool {
+        self.method_prefixes.is_empty()
+            || self
+                .method_prefixes
+                .iter()
+                .any(|p| method.starts_with(p.as_str()))
+    }
+
+    /// The warning for calling `method`, if it is deprecated.
+    pub(crate) fn deprecation(&self, method: &str) -> Option<String> {
+        match self.deprecated.get(method)? {
+            Some(replacement) => Some(format!(
+                "{} is deprecated, use {} instead",
+                method, replacement
+            )),
+            None => Some(format!("{} is deprecated", method)),
+        }
+    }
+
+    /// Warnings for every deprecated method called in a request or batch.
+    pub(crate) fn deprecations(&self, body: &Value) -> Vec<String> {
+        if self.deprecated.is_empty() {
+            return vec![];
+        }
+        let entries = match body {
+            Value::Array(entries) => entries.as_slice(),
+            v => std::slice::from_ref(v),
+        };
+        entries
+            .iter()
+            .filter_map(|e| self.deprecation(e.get("method")?.as_str()?))
+            .collect()
+    }
+
+    /// Takes a batch slot, `Ok(None)` when batches are unlimited.
+    pub(crate) fn batch_permit(&self) -> RPCResult<Option<OwnedSemaphorePermit>> {
+        match &self.max_concurrent_batches {
+            Some(limit) => limit.0.clone().try_acquire_owned().map(Some).map_err(|_| {
+                log::warn!("Rejecting batch, all batch slots taken");
+                RPCError::server_busy()
+            }),
+            None => Ok(None),
+        }
+    }
+
+    /// The codec decoding a request with these headers, `None` for plain JSON.
+    pub(crate) fn request_codec(&self, headers: &HeaderMap) -> Option<Arc<dyn Codec>> {
+        let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+        self.codecs
+            .iter()
+            .find(|c| codec::is_media(content_type, c.content_type()))
+            .cloned()
+    }
+
+    /// The codec encoding the response to a request with these headers, `None` for plain JSON.
+    pub(crate) fn response_codec(&self, headers: &HeaderMap) -> Option<Arc<dyn Codec>> {
+        let accepted: Vec<&str> = headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .collect();
+        let msgpack = self
+            .msgpack
+            .then(|| Arc::new(MsgPackCodec) as Arc<dyn Codec>);
+        self.codecs.iter().cloned().chain(msgpack).find(|c| {
+            accepted
+                .iter()
+                .any(|a| codec::is_media(a, c.content_type()))
+        })
+    }
+
+    pub(crate) fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        match &self.spawner {
+            Some(spawn) => spawn(Box::pin(task)),
+            None => {
+                tokio::spawn(task);
+            }
+        }
+    }
+
+    pub(crate) fn too_deep(&self, body: &[u8]) -> bool {
+        self.max_depth
+            .is_some_and(|limit| json::exceeds_depth(body, limit))
+    }
+
+    pub(crate) fn check_no_params(&self, method: &str, params: &Value) -> RPCResult<()> {
+        let empty = match params {
+            Value::Null => true,
+            Value::Array(a) => a.is_empty(),
+            Value::Object(o) => o.is_empty(),
+            _ => false,
+        };
+        if empty || !self.no_params.contains(method) {
+            return Ok(());
+        }
+        Err(RPCError {
+            data: Some(format!("{} takes no params", method)),
+            ..RPCError::invalid_params()
+        })
+    }
+
+    pub(crate) fn validate_params(&self, method: &str, params: &Value) -> RPCResult<()> {
+        let Some(validator) = self.param_schemas.get(method) else {
+            return Ok(());
+        };
+        let errors: Vec<String> = validator
+            .iter_errors(params)
+            .map(|e| format!("{}: {}", e.instance_path(), e))
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(RPCError {
+                data: Some(errors.join("; ")),
+                ..RPCError::invalid_params()
+            })
+        }
+    }
+
+    pub(crate) fn allows(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|n| n.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|n| n.contains(&ip))
+    }
+
+    pub(crate) fn authorized(&self, headers: &HeaderMap) -> bool {
+        if self.auth.is_empty() {
+            return true;
+        }
+        headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| self.auth.iter().any(|a| a.verify(v)))
+    }
+
+    pub(crate) fn log_body(&self, kind: &str, body: &Value) {
+        if !self.log_bodies || !log::log_enabled!(log::Level::Debug) {
+            return;
+        }
+        match &self.redactor {
+            Some(r) => log::debug!("{} Body: {}", kind, r(body)),
+            None => log::debug!("{} Body: {}", kind, body),
+        }
+    }
+}
+
+/// Builds a [`Redactor`] masking the values at the given JSON pointers (e.g. `/params/0/password`).
+///
+/// For batches the pointers are applied to every entry.
+pub fn redact_paths(paths: &[&str]) -> Redactor {
+    let paths: Vec<String> = paths.iter().map(|p| String::from(*p)).collect();
+    Arc::new(move |body| {
+        let mut body = body.clone();
+        let entries = match body.as_array_mut() {
+            Some(v) => v.iter_mut().collect(),
+            None => vec![&mut body],
+        };
+        for entry in entries {
+            for path in &paths {
+                if let Some(v) = entry.pointer_mut(path) {
+                    *v = Value::from("***");
+                }
+            }
+        }
+        body
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{http_post, testing};
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let config = ServerConfig {
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+            deny: vec!["10.0.0.1/32".parse().unwrap()],
+            ..Default::default()
+        };
+        assert!(config.allows("10.1.2.3".parse().unwrap()));
+        assert!(!config.allows("10.0.0.1".parse().unwrap()));
+        assert!(!config.allows("192.168.0.1".parse().unwrap()));
+        // IPv4-mapped IPv6 peers are matched as the IPv4 address they are.
+        assert!(config.allows("::ffff:10.1.2.3".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn denied_peers_are_disconnected() {
+        let body = br#"{"jsonrpc": "2.0", "id": 1, "method": "echo", "params": []}"#;
+        let addr = testing::serve(ServerConfig {
+            deny: vec!["127.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        })
+        .await;
+        assert!(http_post(&format!("http://{addr}"), body, None)
+            .await
+            .is_err());
+
+        let addr = testing::serve(ServerConfig {
+            allow: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Default::default()
+        })
+        .await;
+        assert!(http_post(&format!("http://{addr}"), body, None)
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,61 @@
+use std::{future::Future, net::SocketAddr, sync::Arc};
+
+use async_trait::async_trait;
+use hyper::{http::Extensions, HeaderMap};
+
+use crate::{RPCError, RPCResult};
+
+tokio::task_local! {
+    static CURRENT: RequestContext;
+}
+
+/// The HTTP request being answered, or that opened the WebSocket connection, for
+/// [`ServerConfig::middleware`](crate::ServerConfig::middleware) to add to and
+/// handlers to read through [`RequestContext::current`].
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    pub peer: SocketAddr,
+    pub headers: HeaderMap,
+    /// Values middleware hands on to handlers, e.g. the caller's user id.
+    pub extensions: Extensions,
+}
+
+impl RequestContext {
+    pub(crate) fn new(peer: SocketAddr, headers: HeaderMap) -> Self {
+        Self {
+            peer,
+            headers,
+            extensions: Extensions::new(),
+        }
+    }
+
+    /// The context of the request currently being handled, if any.
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(Clone::clone).ok()
+    }
+
+    /// A value of type `T` in the current context's extensions, without cloning the rest.
+    pub fn extension<T: Clone + Send + Sync + 'static>() -> Option<T> {
+        CURRENT
+            .try_with(|c| c.extensions.get::<T>().cloned())
+            .ok()
+            .flatten()
+    }
+
+    pub(crate) async fn scope<F: Future>(self, fut: F) -> F::Output {
+        CURRENT.scope(self, fut).await
+    }
+}
+
+/// Runs on every HTTP request, WebSocket upgrades included, after authentication
+/// and before the body is read. An error answers the request in the handler's stead.
+pub type Middleware = Arc<dyn Fn(&mut RequestContext) -> RPCResult<()> + Send + Sync>;
+
+/// Decides what an authenticated caller may call, see
+/// [`ServerConfig::authorizer`](crate::ServerConfig::authorizer).
+#[async_trait]
+pub trait Authorizer: Send + Sync {
+    /// Called before each call is dispatched; an error answers it instead, e.g.
+    /// [`RPCError::forbidden`].
+    async fn authorize(&self, method: &str, ctx: &RequestContext) -> Result<(), RPCError>;
+}
@@ -0,0 +1,70 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Something the server did, streamed to admin clients subscribed with `rpc.events`,
+/// see [`ServerConfig::events`](crate::ServerConfig::events).
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    /// A call passed authorization and was handed to its handler.
+    Dispatched {
+        method: String,
+        peer: Option<SocketAddr>,
+    },
+    /// A dispatched call was answered with an error.
+    Failed {
+        method: String,
+        code: i32,
+        message: String,
+    },
+    /// A call took longer than [`ServerConfig::slow_call`](crate::ServerConfig::slow_call).
+    Slow { method: String, elapsed_ms: u64 },
+}
+
+impl ServerEvent {
+    /// The `type` this event is serialized with, which `rpc.events` filters on.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Dispatched { .. } => "dispatched",
+            Self::Failed { .. } => "failed",
+            Self::Slow { .. } => "slow",
+        }
+    }
+}
+
+/// Fans [`ServerEvent`]s out to every `rpc.events` subscription.
+#[derive(Debug, Clone)]
+pub struct ServerEvents {
+    tx: broadcast::Sender<Arc<ServerEvent>>,
+}
+
+impl ServerEvents {
+    /// `capacity` events are buffered per subscription before slow ones start missing them.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            tx: broadcast::channel(capacity).0,
+        }
+    }
+
+    /// Handlers may emit events of their own. Returns how many subscriptions may receive it.
+    pub fn emit(&self, event: ServerEvent) -> usize {
+        self.tx.send(Arc::new(event)).unwrap_or(0)
+    }
+
+    /// Whether anyone is subscribed, so events needn't be built when nobody is.
+    pub(crate) fn is_watched(&self) -> bool {
+        self.tx.receiver_count() > 0
+    }
+
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<Arc<ServerEvent>> {
+        self.tx.subscribe()
+    }
+}
+
+impl Default for ServerEvents {
+    fn default() -> Self {
+        Self::new(1024)
+    }
+}
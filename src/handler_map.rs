@@ -0,0 +1,109 @@
+use std::{collections::HashMap, future::Future};
+
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use serde_json::Value;
+
+use crate::{Handle, RPCError, RPCResult};
+
+type Method =
+    Box<dyn Fn(Option<Value>) -> BoxFuture<'static, RPCResult<Option<Value>>> + Send + Sync>;
+
+/// A [`Handle`] calling the closure inserted under a method's name, and answering
+/// `-32601` to any other method. Suits services assembled at runtime, e.g. from plugins.
+#[derive(Default)]
+pub struct HandlerMap {
+    methods: HashMap<String, Method>,
+}
+
+impl HandlerMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serves `method` with `f`, replacing whatever served it before.
+    pub fn insert<F, Fut>(&mut self, method: &str, f: F)
+    where
+        F: Fn(Option<Value>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = RPCResult<Option<Value>>> + Send + 'static,
+    {
+        self.methods.insert(
+            String::from(method),
+            Box::new(move |params| Box::pin(f(params))),
+        );
+    }
+
+    pub fn remove(&mut self, method: &str) -> bool {
+        self.methods.remove(method).is_some()
+    }
+
+    pub fn contains(&self, method: &str) -> bool {
+        self.methods.contains_key(method)
+    }
+
+    pub fn methods(&self) -> impl Iterator<Item = &str> {
+        self.methods.keys().map(String::as_str)
+    }
+}
+
+#[async_trait]
+impl Handle for HandlerMap {
+    type Request = Value;
+    type Response = Value;
+
+    async fn handle(&self, method: &str, req: Option<Value>) -> RPCResult<Option<Value>> {
+        match self.methods.get(method) {
+            Some(f) => f(req).await,
+            None => Err(RPCError::unknown_method()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{testing, RpcClient};
+
+    fn methods() -> HandlerMap {
+        let mut map = HandlerMap::new();
+        map.insert("add", |params| async move {
+            let ns: Vec<i64> = serde_json::from_value(params.unwrap_or_default())
+                .map_err(|_| RPCError::invalid_params())?;
+            Ok(Some(json!(ns.iter().sum::<i64>())))
+        });
+        map.insert("busy", |_| async { Err(RPCError::server_busy()) });
+        map.insert("gone", |_| async { Ok(None) });
+        map
+    }
+
+    #[tokio::test]
+    async fn calls_the_closure_of_each_method() {
+        let map = methods();
+        assert_eq!(
+            map.handle("add", Some(json!([1, 2]))).await.unwrap(),
+            Some(json!(3))
+        );
+        let e = map.handle("busy", None).await.unwrap_err();
+        assert_eq!(e.code, RPCError::server_busy().code);
+        let e = map.handle("mul", Some(json!([1, 2]))).await.unwrap_err();
+        assert_eq!(e.code, RPCError::unknown_method().code);
+
+        let mut map = map;
+        assert!(map.remove("gone") && !map.contains("gone"));
+        let mut left: Vec<_> = map.methods().collect();
+        left.sort_unstable();
+        assert_eq!(left, ["add", "busy"]);
+    }
+
+    #[tokio::test]
+    async fn serves_its_methods() {
+        let addr = testing::serve_handle(methods(), Default::default()).await;
+        let client = RpcClient::new(&format!("http://{addr}")).unwrap();
+        let sum: Option<i64> = client.call("add", &[2, 3]).await.unwrap();
+        assert_eq!(sum, Some(5));
+        let e = client.call::<_, Value>("mul", &[2, 3]).await.unwrap_err();
+        assert_eq!(e.code, -32601);
+    }
+}
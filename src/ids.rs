@@ -0,0 +1,151 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+};
+
+use crate::RpcId;
+
+/// Picks the id of each call a client makes.
+pub trait IdGenerator: Send + Sync {
+    fn next_id(&self) -> RpcId;
+}
+
+/// Counts up from 1, so ids are predictable, e.g. in tests. The default.
+#[derive(Debug)]
+pub struct SequentialIds(AtomicI64);
+
+impl SequentialIds {
+    pub fn new() -> Self {
+        Self::starting_at(1)
+    }
+
+    pub fn starting_at(first: i64) -> Self {
+        Self(AtomicI64::new(first))
+    }
+}
+
+impl Default for SequentialIds {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IdGenerator for SequentialIds {
+    fn next_id(&self) -> RpcId {
+        RpcId::Number(self.0.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Random non-negative integers, unique enough across clients sharing one server.
+#[derive(Debug, Default)]
+pub struct RandomIds;
+
+impl IdGenerator for RandomIds {
+    fn next_id(&self) -> RpcId {
+        RpcId::Number((rand::random::<u64>() >> 1) as i64)
+    }
+}
+
+/// Random version 4 UUID strings.
+#[derive(Debug, Default)]
+pub struct UuidIds;
+
+impl IdGenerator for UuidIds {
+    fn next_id(&self) -> RpcId {
+        let n = rand::random::<u128>();
+        // Version 4 in the 13th hex digit, variant `10` in the top bits of the 17th.
+        let n = (n & !(0xf << 76) & !(0x3 << 62)) | (0x4 << 76) | (0x2 << 62);
+        let hex = format!("{:032x}", n);
+        RpcId::String(format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        ))
+    }
+}
+
+/// The generator of one client, shared by its clones.
+#[derive(Clone)]
+pub(crate) struct Ids(pub(crate) Arc<dyn IdGenerator>);
+
+impl Default for Ids {
+    fn default() -> Self {
+        Self(Arc::new(SequentialIds::new()))
+    }
+}
+
+impl fmt::Debug for Ids {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Ids")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::{testing, RpcClient};
+
+    #[test]
+    fn sequential_ids_count_up_from_their_start() {
+        let ids = SequentialIds::new();
+        assert_eq!(
+            (ids.next_id(), ids.next_id()),
+            (RpcId::from(1), RpcId::from(2))
+        );
+        let ids = SequentialIds::starting_at(-1);
+        let taken: Vec<_> = (0..3).map(|_| ids.next_id()).collect();
+        assert_eq!(taken, [RpcId::from(-1), RpcId::from(0), RpcId::from(1)]);
+    }
+
+    #[test]
+    fn random_ids_are_non_negative() {
+        for _ in 0..1000 {
+            match RandomIds.next_id() {
+                RpcId::Number(n) => assert!(n >= 0, "{n}"),
+                id => panic!("{id:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn uuid_ids_are_version_4() {
+        for _ in 0..100 {
+            let RpcId::String(uuid) = UuidIds.next_id() else {
+                panic!("not a string");
+            };
+            let groups: Vec<_> = uuid.split('-').map(str::len).collect();
+            assert_eq!(groups, [8, 4, 4, 4, 12], "{uuid}");
+            assert!(
+                uuid.chars().all(|c| c == '-' || c.is_ascii_hexdigit()),
+                "{uuid}"
+            );
+            assert_eq!(&uuid[14..15], "4", "{uuid}");
+            assert!("89ab".contains(&uuid[19..20]), "{uuid}");
+        }
+    }
+
+    #[tokio::test]
+    async fn clients_send_the_ids_generated() {
+        let addr = testing::serve(Default::default()).await;
+        let client = RpcClient::builder()
+            .url(&format!("http://{addr}"))
+            .id_generator(SequentialIds::starting_at(10))
+            .build()
+            .unwrap();
+        for id in [10, 11] {
+            let (_, resp) = client
+                .call_with_status::<_, Value>("echo", &[1])
+                .await
+                .unwrap();
+            assert_eq!(resp.id, RpcId::from(id));
+        }
+    }
+}
@@ -0,0 +1,350 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use serde::{
+    de::{self, IgnoredAny},
+    ser, Deserialize, Deserializer, Serialize,
+};
+use serde_json::Value;
+
+/// Nesting depth of the arrays and objects in `body`, without parsing it.
+///
+/// Stops counting once `limit` is exceeded, so malicious input is rejected in one cheap pass.
+pub(crate) fn exceeds_depth(body: &[u8], limit: usize) -> bool {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for b in body {
+        if in_string {
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match b {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                if depth > limit {
+                    return true;
+                }
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Whether a request body holds one request or a batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Shape {
+    Single,
+    Batch,
+}
+
+/// The shape of `body` from its first byte past any JSON whitespace, `None` if it
+/// opens neither an object nor an array and so can't be a request.
+pub(crate) fn shape(body: &[u8]) -> Option<Shape> {
+    match body
+        .iter()
+        .find(|b| !matches!(b, b' ' | b'\t' | b'\n' | b'\r'))
+    {
+        Some(b'{') => Some(Shape::Single),
+        Some(b'[') => Some(Shape::Batch),
+        _ => None,
+    }
+}
+
+/// The shape of an already parsed body.
+pub(crate) fn value_shape(body: &Value) -> Option<Shape> {
+    match body {
+        Value::Object(_) => Some(Shape::Single),
+        Value::Array(_) => Some(Shape::Batch),
+        _ => None,
+    }
+}
+
+/// The first member repeated within one request of `body`, a request or a batch.
+///
+/// serde_json silently keeps the last of duplicates, so finding them takes a pass of
+/// its own. Finds nothing in invalid JSON, leaving that for the parser to report.
+pub(crate) fn duplicate_key(body: &[u8]) -> Option<String> {
+    serde_json::from_slice::<DuplicateKey>(body).ok()?.0
+}
+
+struct DuplicateKey(Option<String>);
+
+impl<'de> Deserialize<'de> for DuplicateKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(DuplicateKeyVisitor)
+    }
+}
+
+struct DuplicateKeyVisitor;
+
+macro_rules! no_keys {
+    ($($name:ident($arg:ty)),* $(,)?) => {
+        $(fn $name<E: de::Error>(self, _: $arg) -> Result<DuplicateKey, E> {
+            Ok(DuplicateKey(None))
+        })*
+    };
+}
+
+impl<'de> de::Visitor<'de> for DuplicateKeyVisitor {
+    type Value = DuplicateKey;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a request or a batch")
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<DuplicateKey, A::Error> {
+        let mut seen = HashSet::new();
+        let mut duplicate = None;
+        while let Some(key) = map.next_key::<String>()? {
+            map.next_value::<IgnoredAny>()?;
+            if duplicate.is_none() && !seen.insert(key.clone()) {
+                duplicate = Some(key);
+            }
+        }
+        Ok(DuplicateKey(duplicate))
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<DuplicateKey, A::Error> {
+        let mut duplicate = None;
+        while let Some(DuplicateKey(d)) = seq.next_element()? {
+            duplicate = duplicate.or(d);
+        }
+        Ok(DuplicateKey(duplicate))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<DuplicateKey, E> {
+        Ok(DuplicateKey(None))
+    }
+
+    // Batch entries that aren't objects are answered `-32600` later on.
+    no_keys!(
+        visit_bool(bool),
+        visit_i64(i64),
+        visit_u64(u64),
+        visit_f64(f64),
+        visit_str(&str),
+    );
+}
+
+/// Whether `body`, a response as the server encodes it, carries a result rather
+/// than an error. Relies on `result` or `error` always following `jsonrpc`.
+pub(crate) fn is_result(body: &str) -> bool {
+    body.starts_with(r#"{"jsonrpc":"2.0","result":"#)
+}
+
+/// The fields of a result to keep, parsed from comma-separated, dot-separated
+/// paths such as `name,owner.id`.
+#[derive(Debug, Default)]
+pub(crate) struct Projection {
+    /// Set where a path ends, keeping everything below it.
+    whole: bool,
+    fields: HashMap<String, Projection>,
+}
+
+impl Projection {
+    pub(crate) fn parse(paths: &str) -> Self {
+        let mut root = Self::default();
+        for path in paths.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let node = path.split('.').fold(&mut root, |node, key| {
+                node.fields.entry(String::from(key)).or_default()
+            });
+            node.whole = true;
+        }
+        root
+    }
+
+    /// Trims the objects in `value`, element by element for arrays.
+    pub(crate) fn apply(&self, value: Value) -> Value {
+        if self.whole || self.fields.is_empty() {
+            return value;
+        }
+        match value {
+            Value::Object(map) => Value::Object(
+                map.into_iter()
+                    .filter_map(|(k, v)| {
+                        let v = self.fields.get(&k)?.apply(v);
+                        Some((k, v))
+                    })
+                    .collect(),
+            ),
+            Value::Array(items) => Value::Array(items.into_iter().map(|v| self.apply(v)).collect()),
+            v => v,
+        }
+    }
+}
+
+/// Whether `value` serializes as nothing at all, i.e. `None` or `()`.
+///
+/// Stops at the first byte of real data, so it costs nothing for large values.
+pub(crate) fn is_absent<T: Serialize>(value: &T) -> bool {
+    value.serialize(AbsenceCheck).is_ok()
+}
+
+#[derive(Debug)]
+struct Present;
+
+impl fmt::Display for Present {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("value is present")
+    }
+}
+
+impl std::error::Error for Present {}
+
+impl ser::Error for Present {
+    fn custom<T: fmt::Display>(_: T) -> Self {
+        Present
+    }
+}
+
+/// A serializer that only succeeds for `None` and unit.
+struct AbsenceCheck;
+
+macro_rules! present {
+    ($($name:ident($($arg:ty),*)),* $(,)?) => {
+        $(fn $name(self, $(_: $arg),*) -> Result<(), Present> {
+            Err(Present)
+        })*
+    };
+}
+
+impl ser::Serializer for AbsenceCheck {
+    type Ok = ();
+    type Error = Present;
+    type SerializeSeq = ser::Impossible<(), Present>;
+    type SerializeTuple = ser::Impossible<(), Present>;
+    type SerializeTupleStruct = ser::Impossible<(), Present>;
+    type SerializeTupleVariant = ser::Impossible<(), Present>;
+    type SerializeMap = ser::Impossible<(), Present>;
+    type SerializeStruct = ser::Impossible<(), Present>;
+    type SerializeStructVariant = ser::Impossible<(), Present>;
+
+    fn serialize_none(self) -> Result<(), Present> {
+        Ok(())
+    }
+
+    fn serialize_unit(self) -> Result<(), Present> {
+        Ok(())
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, _: &T) -> Result<(), Present> {
+        Err(Present)
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        _: &T,
+    ) -> Result<(), Present> {
+        Err(Present)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> Result<(), Present> {
+        Err(Present)
+    }
+
+    present!(
+        serialize_bool(bool),
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_char(char),
+        serialize_str(&str),
+        serialize_bytes(&[u8]),
+        serialize_unit_struct(&'static str),
+        serialize_unit_variant(&'static str, u32, &'static str),
+    );
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, Present> {
+        Err(Present)
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, Present> {
+        Err(Present)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleStruct, Present> {
+        Err(Present)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleVariant, Present> {
+        Err(Present)
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, Present> {
+        Err(Present)
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self::SerializeStruct, Present> {
+        Err(Present)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeStructVariant, Present> {
+        Err(Present)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_nesting_outside_strings() {
+        assert!(!exceeds_depth(br#"{"a": [1, {"b": []}]}"#, 4));
+        assert!(exceeds_depth(br#"{"a": [1, {"b": []}]}"#, 3));
+        assert!(!exceeds_depth(br#"{"a": "[[[[\"]]]]"}"#, 1));
+
+        let nested = format!("{}{}", "[".repeat(100_000), "]".repeat(100_000));
+        assert!(exceeds_depth(nested.as_bytes(), 64));
+    }
+
+    #[test]
+    fn shapes_come_from_the_first_byte_past_whitespace() {
+        assert_eq!(shape(b"{}"), Some(Shape::Single));
+        assert_eq!(shape(b" \t\r\n[{}]"), Some(Shape::Batch));
+        assert_eq!(shape(b"\n  {\"a\": 1}"), Some(Shape::Single));
+        for body in [&b""[..], b"   ", b"42", b"\"[\"", b"null", b"\x0c[]"] {
+            assert_eq!(shape(body), None, "{:?}", String::from_utf8_lossy(body));
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Linear buckets per power of two, so a percentile is off by at most 1/16.
+const SUB_BUCKETS: u64 = 16;
+/// Durations are counted in microseconds, up to about 12 days.
+const MAX_MICROS: u64 = (1 << 40) - 1;
+
+/// How long each method's calls took, kept per method in a histogram of a few
+/// hundred counters whatever the number of calls, see
+/// [`ServerConfig::latencies`](crate::ServerConfig::latencies). Clones share the
+/// same histograms.
+#[derive(Debug, Clone, Default)]
+pub struct CallLatencies {
+    methods: Arc<Mutex<HashMap<String, Histogram>>>,
+}
+
+impl CallLatencies {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, method: &str, elapsed: Duration) {
+        let mut methods = self.methods.lock().unwrap();
+        match methods.get_mut(method) {
+            Some(h) => h.record(elapsed),
+            None => {
+                let mut h = Histogram::default();
+                h.record(elapsed);
+                methods.insert(String::from(method), h);
+            }
+        }
+    }
+
+    /// The duration `p` percent of `method`'s calls took at most, `p` being clamped to
+    /// 0 to 100, or `None` before any call to it was recorded.
+    pub fn latency_percentile(&self, method: &str, p: f64) -> Option<Duration> {
+        self.methods.lock().unwrap().get(method)?.percentile(p)
+    }
+
+    /// How many calls to `method` were recorded.
+    pub fn count(&self, method: &str) -> u64 {
+        self.methods
+            .lock()
+            .unwrap()
+            .get(method)
+            .map_or(0, |h| h.count)
+    }
+
+    pub fn methods(&self) -> Vec<String> {
+        self.methods.lock().unwrap().keys().cloned().collect()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    max: u64,
+}
+
+impl Histogram {
+    fn record(&mut self, elapsed: Duration) {
+        let micros = (elapsed.as_micros() as u64).min(MAX_MICROS);
+        let i = bucket(micros);
+        if self.buckets.len() <= i {
+            self.buckets.resize(i + 1, 0);
+        }
+        self.buckets[i] += 1;
+        self.count += 1;
+        self.max = self.max.max(micros);
+    }
+
+    fn percentile(&self, p: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((p.clamp(0.0, 100.0) / 100.0 * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                let micros = bucket_top(i).min(self.max);
+                return Some(Duration::from_micros(micros));
+            }
+        }
+        Some(Duration::from_micros(self.max))
+    }
+}
+
+/// Values below [`SUB_BUCKETS`] get a bucket each; above, each power of two is split
+/// into [`SUB_BUCKETS`] equal buckets.
+fn bucket(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+    let exp = 63 - micros.leading_zeros() as u64;
+    let shift = exp - SUB_BUCKETS.trailing_zeros() as u64;
+    (SUB_BUCKETS * (shift + 1) + (micros >> shift) - SUB_BUCKETS) as usize
+}
+
+/// The largest value falling into bucket `i`.
+fn bucket_top(i: usize) -> u64 {
+    let i = i as u64;
+    if i < SUB_BUCKETS {
+        return i;
+    }
+    let shift = i / SUB_BUCKETS - 1;
+    let sub = i % SUB_BUCKETS + SUB_BUCKETS;
+    ((sub + 1) << shift) - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_of_known_durations() {
+        let latencies = CallLatencies::new();
+        assert_eq!(latencies.latency_percentile("m", 50.0), None);
+        for ms in 1..=100 {
+            latencies.record("m", Duration::from_millis(ms));
+        }
+        assert_eq!(latencies.count("m"), 100);
+        assert_eq!(latencies.methods(), ["m"]);
+
+        for (p, ms) in [(0.0, 1), (50.0, 50), (90.0, 90), (99.0, 99)] {
+            let got = latencies.latency_percentile("m", p).unwrap();
+            let want = Duration::from_millis(ms);
+            assert!(got >= want && got <= want + want / 16, "p{p} was {got:?}");
+        }
+        let max = Duration::from_millis(100);
+        assert_eq!(latencies.latency_percentile("m", 100.0), Some(max));
+        assert_eq!(latencies.latency_percentile("m", 150.0), Some(max));
+    }
+}
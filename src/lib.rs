@@ -1,10 +1,69 @@
 #![deny(warnings, unused_crate_dependencies)]
 
+// Only the benches use it.
+#[cfg(test)]
+use criterion as _;
+
+mod auth;
+pub use auth::*;
+
+mod json;
+
 mod types;
 pub use types::*;
 
+mod ids;
+pub use ids::*;
+
 mod client;
 pub use client::*;
 
+mod retry;
+pub use retry::*;
+
+mod rpc_client;
+pub use rpc_client::*;
+
+mod cache;
+pub use cache::*;
+
+mod batcher;
+pub use batcher::*;
+
+mod pending;
+pub use pending::*;
+
+mod config;
+pub use config::*;
+
+mod context;
+pub use context::*;
+
+mod events;
+pub use events::*;
+
+mod latency;
+pub use latency::*;
+
+mod codec;
+pub use codec::*;
+
+mod redirect;
+pub use redirect::*;
+
 mod server;
 pub use server::*;
+
+mod handler_map;
+pub use handler_map::*;
+
+mod trace;
+pub use trace::*;
+
+mod ws;
+pub use ws::*;
+mod ws_client;
+pub use ws_client::*;
+
+#[cfg(test)]
+mod testing;
@@ -0,0 +1,197 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde_json::Value;
+use tokio::sync::oneshot;
+
+use crate::{RPCError, RPCResult, RpcId};
+
+/// `None` once closed.
+type Senders<T> = Arc<Mutex<Option<HashMap<RpcId, oneshot::Sender<T>>>>>;
+
+/// Calls sent but not yet answered, matched to their responses by id. One side
+/// [`register`](Self::register)s each call before sending it and waits on what it
+/// gets back; whatever reads responses off the transport [`resolve`](Self::resolve)s
+/// them. Clones share the same calls.
+///
+/// [`WsClient`](crate::WsClient) is built on this; a custom transport can be too.
+pub struct PendingRequests<T = Value> {
+    senders: Senders<T>,
+}
+
+impl<T> PendingRequests<T> {
+    pub fn new() -> Self {
+        Self {
+            senders: Arc::new(Mutex::new(Some(HashMap::new()))),
+        }
+    }
+
+    /// Waits for the response to `id`. A call already waiting on the same id is
+    /// cancelled, so ids should be unique among calls in flight. Once
+    /// [`close`](Self::close)d, the call is cancelled straight away.
+    pub fn register(&self, id: RpcId) -> PendingResponse<T> {
+        let (tx, rx) = oneshot::channel();
+        if let Some(senders) = self.senders.lock().unwrap().as_mut() {
+            senders.insert(id.clone(), tx);
+        }
+        PendingResponse {
+            id,
+            rx,
+            senders: self.senders.clone(),
+        }
+    }
+
+    /// Hands `response` to the call waiting on `id`. Gives it back if there is none,
+    /// e.g. because that call timed out or was never made.
+    pub fn resolve(&self, id: &RpcId, response: T) -> Result<(), T> {
+        let tx = self
+            .senders
+            .lock()
+            .unwrap()
+            .as_mut()
+            .and_then(|s| s.remove(id));
+        match tx {
+            Some(tx) => tx.send(response),
+            None => Err(response),
+        }
+    }
+
+    /// Stops waiting for `id`, failing its call with `request_cancelled`. Whether it was
+    /// still waiting.
+    pub fn cancel(&self, id: &RpcId) -> bool {
+        let mut senders = self.senders.lock().unwrap();
+        senders.as_mut().is_some_and(|s| s.remove(id).is_some())
+    }
+
+    /// Fails every call still waiting, e.g. after reconnecting.
+    pub fn clear(&self) {
+        if let Some(senders) = self.senders.lock().unwrap().as_mut() {
+            senders.clear();
+        }
+    }
+
+    /// Fails every call still waiting and every one registered later, e.g. when the
+    /// connection closes for good.
+    pub fn close(&self) {
+        self.senders.lock().unwrap().take();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.senders.lock().unwrap().is_none()
+    }
+
+    pub fn len(&self) -> usize {
+        self.senders
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, HashMap::len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Default for PendingRequests<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Clone for PendingRequests<T> {
+    fn clone(&self) -> Self {
+        Self {
+            senders: self.senders.clone(),
+        }
+    }
+}
+
+/// One registered call, see [`PendingRequests::register`]. Dropping it stops waiting,
+/// so a response arriving later is handed back by `resolve`.
+pub struct PendingResponse<T = Value> {
+    id: RpcId,
+    rx: oneshot::Receiver<T>,
+    senders: Senders<T>,
+}
+
+impl<T> PendingResponse<T> {
+    pub fn id(&self) -> &RpcId {
+        &self.id
+    }
+
+    /// The response, `request_timeout` if none arrives within `timeout`, or
+    /// `request_cancelled` if the call is cancelled, cleared or closed first.
+    pub async fn recv(mut self, timeout: Option<Duration>) -> RPCResult<T> {
+        let rx = &mut self.rx;
+        let received = match timeout {
+            Some(d) => tokio::time::timeout(d, rx)
+                .await
+                .map_err(|_| RPCError::request_timeout())?,
+            None => rx.await,
+        };
+        received.map_err(|_| RPCError::request_cancelled())
+    }
+}
+
+impl<T> Drop for PendingResponse<T> {
+    fn drop(&mut self) {
+        self.rx.close();
+        let mut senders = self.senders.lock().unwrap();
+        let Some(senders) = senders.as_mut() else {
+            return;
+        };
+        // A newer call registered under the same id keeps waiting.
+        if senders
+            .get(&self.id)
+            .is_some_and(oneshot::Sender::is_closed)
+        {
+            senders.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn resolves_times_out_and_drops_calls() {
+        let pending = PendingRequests::<Value>::new();
+        let answered = pending.register(RpcId::from(1));
+        assert!(pending.resolve(&RpcId::from(1), Value::from("one")).is_ok());
+        assert_eq!(answered.recv(None).await.unwrap(), "one");
+        assert_eq!(
+            pending.resolve(&RpcId::from(1), Value::Null),
+            Err(Value::Null)
+        );
+
+        let late = pending.register(RpcId::from(2));
+        let timeout = Some(Duration::from_millis(10));
+        let e = late.recv(timeout).await.unwrap_err();
+        assert_eq!(e.code, RPCError::request_timeout().code);
+        assert!(pending.resolve(&RpcId::from(2), Value::Null).is_err());
+
+        let dropped = pending.register(RpcId::from(3));
+        assert_eq!(pending.len(), 1);
+        drop(dropped);
+        assert!(pending.is_empty());
+        assert!(pending.resolve(&RpcId::from(3), Value::Null).is_err());
+    }
+
+    #[tokio::test]
+    async fn closing_fails_waiting_and_later_calls() {
+        let pending = PendingRequests::<Value>::new();
+        let waiting = pending.register(RpcId::from(1));
+        pending.close();
+
+        let cancelled = RPCError::request_cancelled().code;
+        assert_eq!(waiting.recv(None).await.unwrap_err().code, cancelled);
+        let later = pending.register(RpcId::from(2));
+        assert_eq!(later.recv(None).await.unwrap_err().code, cancelled);
+        assert!(pending.is_empty());
+    }
+}
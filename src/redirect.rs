@@ -0,0 +1,41 @@
+use std::{cell::RefCell, future::Future};
+
+use hyper::StatusCode;
+
+tokio::task_local! {
+    static REDIRECT: RefCell<Option<Redirect>>;
+}
+
+/// An HTTP redirect answered instead of the JSON-RPC response, see [`redirect`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    pub status: StatusCode,
+    pub location: String,
+}
+
+/// Called from a handler, answers the HTTP request with `status` and a `Location`
+/// header instead of the JSON-RPC response the handler returns.
+///
+/// Only single requests over HTTP can be redirected; in batches, over WebSocket or
+/// with a non-3xx `status` nothing happens and `false` is returned.
+pub fn redirect(location: &str, status: StatusCode) -> bool {
+    status.is_redirection()
+        && REDIRECT
+            .try_with(|r| {
+                *r.borrow_mut() = Some(Redirect {
+                    status,
+                    location: String::from(location),
+                })
+            })
+            .is_ok()
+}
+
+/// Runs `f`, returning any redirect requested while it ran.
+pub(crate) async fn capture<F: Future>(f: F) -> (F::Output, Option<Redirect>) {
+    REDIRECT
+        .scope(RefCell::new(None), async {
+            let out = f.await;
+            (out, REDIRECT.with(|r| r.borrow_mut().take()))
+        })
+        .await
+}
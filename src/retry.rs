@@ -0,0 +1,111 @@
+use std::{collections::HashSet, time::Duration};
+
+use rand::Rng;
+
+/// How much of the backoff window a retry delay is randomized over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Jitter {
+    /// Wait the full backoff.
+    #[default]
+    None,
+    /// Wait anywhere between zero and the backoff.
+    Full,
+    /// Wait half the backoff plus anywhere up to the other half.
+    Equal,
+}
+
+/// Retries of a client call that failed in transport or with one of `codes`,
+/// up to `max_attempts` calls in total. Servers retry handlers the same way, see
+/// [`ServerConfig::handler_retries`](crate::ServerConfig::handler_retries).
+///
+/// The n-th retry waits `base_delay * 2^(n-1)`, capped at `max_delay` and then
+/// randomized per `jitter`, so clients failing together don't retry together.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: usize,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub jitter: Jitter,
+    pub codes: HashSet<i32>,
+}
+
+impl Default for RetryPolicy {
+    /// Three attempts, from 100ms up to 10s apart, on `server_busy` and `temporary_failure`.
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            jitter: Jitter::None,
+            codes: HashSet::from([-32000, -32010]),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The backoff window before retry `retry`, counting from 1.
+    pub fn backoff(&self, retry: usize) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1).min(32) as u32);
+        self.base_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+
+    /// The delay before retry `retry`, jittered with `rng`.
+    pub fn delay_with<R: Rng + ?Sized>(&self, retry: usize, rng: &mut R) -> Duration {
+        let window = self.backoff(retry);
+        match self.jitter {
+            Jitter::None => window,
+            Jitter::Full => window.mul_f64(rng.random_range(0.0..=1.0)),
+            Jitter::Equal => window / 2 + (window / 2).mul_f64(rng.random_range(0.0..=1.0)),
+        }
+    }
+
+    pub fn delay(&self, retry: usize) -> Duration {
+        self.delay_with(retry, &mut rand::rng())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    fn policy(jitter: Jitter) -> RetryPolicy {
+        RetryPolicy {
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            jitter,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max_delay() {
+        let p = policy(Jitter::None);
+        let delays: Vec<_> = (1..=6).map(|r| p.backoff(r).as_millis()).collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(p.backoff(usize::MAX), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn jittered_delays_stay_in_their_window() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let window = Duration::from_millis(400);
+        for (jitter, low) in [(Jitter::Full, Duration::ZERO), (Jitter::Equal, window / 2)] {
+            let p = policy(jitter);
+            let delays: Vec<_> = (0..1000).map(|_| p.delay_with(3, &mut rng)).collect();
+            assert!(delays.iter().all(|d| (low..=window).contains(d)));
+            // Spread over the window rather than bunched up.
+            let mean = delays.iter().sum::<Duration>() / 1000;
+            let middle = (low + window) / 2;
+            assert!(
+                mean.abs_diff(middle) < Duration::from_millis(20),
+                "{mean:?}"
+            );
+        }
+        assert_eq!(policy(Jitter::None).delay_with(3, &mut rng), window);
+    }
+}
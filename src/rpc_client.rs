@@ -0,0 +1,670 @@
+use std::{
+    collections::HashSet,
+    fmt::{self, Debug},
+    io::{self, Read},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use futures::{future::BoxFuture, FutureExt};
+use http_body_util::Full;
+use hyper::{
+    header::{self, HeaderName, HeaderValue},
+    HeaderMap, Request, Uri,
+};
+use hyper_tls::HttpsConnector;
+use hyper_util::{
+    client::legacy::{
+        connect::{
+            dns::{GaiResolver, Name},
+            HttpConnector,
+        },
+        Client,
+    },
+    rt::TokioExecutor,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    client::{
+        batch_headers, batch_response, call_headers, call_response, header_map, send_request_parts,
+        status_envelope,
+    },
+    codec::MSGPACK,
+    ids::Ids,
+    verify_batch_ids, Auth, IdGenerator, RPCError, RPCRequest, RPCResponse, RPCResult,
+    RequestSigner, RetryPolicy, RpcId,
+};
+
+/// A client bound to one endpoint, reusing its connections across calls.
+#[derive(Clone)]
+pub struct RpcClient {
+    url: String,
+    client: Client<HttpsConnector<HttpConnector<Resolver>>, Full<Bytes>>,
+    msgpack: bool,
+    signer: Option<RequestSigner>,
+    auth: Option<Auth>,
+    retry: Option<RetryPolicy>,
+    idempotent: Arc<HashSet<String>>,
+    ids: Ids,
+    log_calls: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct RpcClientBuilder {
+    url: Option<String>,
+    accept_invalid_certs: bool,
+    msgpack: bool,
+    signer: Option<RequestSigner>,
+    auth: Option<Auth>,
+    retry: Option<RetryPolicy>,
+    idempotent: HashSet<String>,
+    connect_to: Option<IpAddr>,
+    ids: Ids,
+    log_calls: bool,
+}
+
+impl RpcClientBuilder {
+    pub fn url(mut self, url: &str) -> Self {
+        self.url = Some(String::from(url));
+        self
+    }
+
+    /// Skips TLS certificate and hostname verification, for self-signed dev/CI servers only.
+    ///
+    /// Anyone on the network path can impersonate the server once this is enabled.
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
+    /// Ask for MessagePack responses, which the server may or may not honour.
+    pub fn msgpack(mut self, msgpack: bool) -> Self {
+        self.msgpack = msgpack;
+        self
+    }
+
+    /// Credentials sent with every call unless it passes its own.
+    pub fn auth(mut self, auth: Auth) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    pub fn bearer(self, token: &str) -> Self {
+        self.auth(Auth::bearer(token))
+    }
+
+    /// Retry [`RpcClient::call`] and [`RpcClient::call_with_auth`] per `policy`, see also
+    /// [`RpcClientBuilder::idempotent`].
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Methods safe to run twice, which the [`RpcClientBuilder::retry`] policy retries
+    /// however they fail. Other methods are only retried when connecting failed, so the
+    /// server never saw them; by default that is every method.
+    pub fn idempotent<I, S>(mut self, methods: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.idempotent = methods.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Connects to `ip` instead of resolving the url's host, which still names the
+    /// server for TLS SNI, certificate verification and the `Host` header.
+    ///
+    /// Needed to reach one server by address while its certificate names a host, e.g.
+    /// a backend behind a load balancer or a pinned certificate. The url's port is kept.
+    pub fn connect_to(mut self, ip: IpAddr) -> Self {
+        self.connect_to = Some(ip);
+        self
+    }
+
+    /// How call ids are picked, [`SequentialIds`] by default.
+    pub fn id_generator(mut self, ids: impl IdGenerator + 'static) -> Self {
+        self.ids = Ids(Arc::new(ids));
+        self
+    }
+
+    /// Log the method, id, url, HTTP status and round-trip time of every call at
+    /// debug level. Off by default.
+    pub fn log_calls(mut self, log: bool) -> Self {
+        self.log_calls = log;
+        self
+    }
+
+    /// Sign every request body, see [`RequestSigner`].
+    pub fn signer(mut self, signer: RequestSigner) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    pub fn build(self) -> Result<RpcClient> {
+        let url = self
+            .url
+            .ok_or_else(|| anyhow!("RpcClient requires a url"))?;
+        parse_endpoint(&url)?;
+
+        let mut tls = native_tls::TlsConnector::builder();
+        if self.accept_invalid_certs {
+            log::warn!(
+                "TLS certificate verification is DISABLED for {}, never use this in production",
+                url
+            );
+            tls.danger_accept_invalid_certs(true)
+                .danger_accept_invalid_hostnames(true);
+        }
+        let resolver = match self.connect_to {
+            Some(ip) => Resolver::Fixed(ip),
+            None => Resolver::System(GaiResolver::new()),
+        };
+        let mut http = HttpConnector::new_with_resolver(resolver);
+        http.enforce_http(false);
+        let connector = HttpsConnector::from((http, tls.build()?.into()));
+
+        Ok(RpcClient {
+            url,
+            client: Client::builder(TokioExecutor::new()).build(connector),
+            msgpack: self.msgpack,
+            signer: self.signer,
+            auth: self.auth,
+            retry: self.retry,
+            idempotent: Arc::new(self.idempotent),
+            ids: self.ids,
+            log_calls: self.log_calls,
+        })
+    }
+}
+
+impl RpcClient {
+    pub fn new(url: &str) -> Result<Self> {
+        Self::builder().url(url).build()
+    }
+
+    pub fn builder() -> RpcClientBuilder {
+        RpcClientBuilder::default()
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Calls `method` with the client's default auth, if any.
+    pub async fn call<P, R>(&self, method: &str, params: &P) -> RPCResult<Option<R>>
+    where
+        R: for<'de> Deserialize<'de> + Debug,
+        P: Serialize,
+    {
+        self.call_auth(method, params, self.auth.as_ref()).await
+    }
+
+    /// Like [`RpcClient::call`], with `auth` instead of the default one.
+    pub async fn call_with_auth<P, R>(
+        &self,
+        method: &str,
+        params: &P,
+        auth: &Auth,
+    ) -> RPCResult<Option<R>>
+    where
+        R: for<'de> Deserialize<'de> + Debug,
+        P: Serialize,
+    {
+        self.call_auth(method, params, Some(auth)).await
+    }
+
+    async fn call_auth<P, R>(
+        &self,
+        method: &str,
+        params: &P,
+        auth: Option<&Auth>,
+    ) -> RPCResult<Option<R>>
+    where
+        R: for<'de> Deserialize<'de> + Debug,
+        P: Serialize,
+    {
+        let req = self.request(method, params);
+        let s = serde_json::to_vec(&req).map_err(|e| RPCError::internal_error(format!("{e:?}")))?;
+        let id = req.id.clone().unwrap_or_default();
+
+        let idempotent = self.idempotent.contains(method);
+        let mut attempt = 1;
+        loop {
+            // Transport failures and gateway/overload statuses are worth retrying, but
+            // only a failed connect guarantees the call didn't run.
+            let call = Call::Single(method, &id);
+            let (r, retries) = match self.post(call, s.clone(), &call_headers(auth), None).await {
+                Ok((status_code, bytes)) => {
+                    let transient = matches!(status_code.as_u16(), 502..=504);
+                    (
+                        call_response(&id, status_code, bytes),
+                        idempotent && transient,
+                    )
+                }
+                Err(e) => {
+                    let retries = idempotent || not_connected(&e);
+                    (Err(RPCError::internal_error(format!("{e:?}"))), retries)
+                }
+            };
+            match (&self.retry, r) {
+                (Some(policy), Err(e))
+                    if attempt < policy.max_attempts
+                        && (retries || idempotent && policy.codes.contains(&e.code)) =>
+                {
+                    let delay = policy.delay(attempt);
+                    log::debug!(
+                        "Retrying {} after error {} in {:?}, attempt {}",
+                        method,
+                        e.code,
+                        delay,
+                        attempt
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                (_, r) => return r,
+            }
+        }
+    }
+
+    /// Like [`RpcClient::call`], but returns the HTTP status next to the whole
+    /// response envelope, see [`call_with_status`](crate::call_with_status).
+    pub async fn call_with_status<P, R>(
+        &self,
+        method: &str,
+        params: &P,
+    ) -> RPCResult<(hyper::StatusCode, RPCResponse<R>)>
+    where
+        R: for<'de> Deserialize<'de> + Debug,
+        P: Serialize,
+    {
+        let req = self.request(method, params);
+        let s = serde_json::to_vec(&req).map_err(|e| RPCError::internal_error(format!("{e:?}")))?;
+
+        let id = req.id.unwrap_or_default();
+
+        let (status_code, bytes) = self
+            .post(
+                Call::Single(method, &id),
+                s,
+                &call_headers(self.auth.as_ref()),
+                None,
+            )
+            .await
+            .map_err(|e| RPCError::internal_error(format!("{e:?}")))?;
+
+        status_envelope(&id, status_code, bytes)
+    }
+
+    /// Like [`RpcClient::call`], with `headers` added to (or replacing) the default ones,
+    /// including the default auth.
+    pub async fn call_with_headers<P, R>(
+        &self,
+        method: &str,
+        params: &P,
+        headers: &HeaderMap,
+    ) -> RPCResult<Option<R>>
+    where
+        R: for<'de> Deserialize<'de> + Debug,
+        P: Serialize,
+    {
+        let req = self.request(method, params);
+        let s = serde_json::to_vec(&req).map_err(|e| RPCError::internal_error(format!("{e:?}")))?;
+
+        let id = req.id.unwrap_or_default();
+
+        let (status_code, bytes) = self
+            .post(
+                Call::Single(method, &id),
+                s,
+                &call_headers(self.auth.as_ref()),
+                Some(headers),
+            )
+            .await
+            .map_err(|e| RPCError::internal_error(format!("{e:?}")))?;
+
+        call_response(&id, status_code, bytes)
+    }
+
+    /// Sends `requests` as one batch with the client's default auth, if any.
+    pub async fn batch_call<P, R>(
+        &self,
+        requests: &Vec<RPCRequest<P>>,
+    ) -> Result<Vec<RPCResponse<R>>>
+    where
+        R: for<'de> Deserialize<'de>,
+        P: Serialize + Clone,
+    {
+        self.batch_call_auth(requests, self.auth.as_ref()).await
+    }
+
+    /// Like [`RpcClient::batch_call`], failing unless the responses carry exactly the
+    /// `expected` ids, see [`verify_batch_ids`].
+    pub async fn batch_call_expecting<P, R>(
+        &self,
+        requests: &Vec<RPCRequest<P>>,
+        expected: &[RpcId],
+    ) -> Result<Vec<RPCResponse<R>>>
+    where
+        R: for<'de> Deserialize<'de>,
+        P: Serialize + Clone,
+    {
+        let responses = self.batch_call(requests).await?;
+        verify_batch_ids(expected, &responses)?;
+        Ok(responses)
+    }
+
+    /// Like [`RpcClient::batch_call`], with `auth` instead of the default one.
+    pub async fn batch_call_with_auth<P, R>(
+        &self,
+        requests: &Vec<RPCRequest<P>>,
+        auth: &Auth,
+    ) -> Result<Vec<RPCResponse<R>>>
+    where
+        R: for<'de> Deserialize<'de>,
+        P: Serialize + Clone,
+    {
+        self.batch_call_auth(requests, Some(auth)).await
+    }
+
+    async fn batch_call_auth<P, R>(
+        &self,
+        requests: &Vec<RPCRequest<P>>,
+        auth: Option<&Auth>,
+    ) -> Result<Vec<RPCResponse<R>>>
+    where
+        R: for<'de> Deserialize<'de>,
+        P: Serialize + Clone,
+    {
+        let s = serde_json::to_vec(&requests)?;
+
+        let call = Call::Batch(requests.len());
+        let (status_code, bytes) = self.post(call, s, &batch_headers(auth), None).await?;
+        batch_response(status_code, bytes)
+    }
+
+    fn request<P: Clone>(&self, method: &str, params: P) -> RPCRequest<P> {
+        RPCRequest {
+            id: Some(self.ids.0.next_id()),
+            ..RPCRequest::new(method, params)
+        }
+    }
+
+    /// Posts `body`, handing back the response as JSON whatever it was encoded with,
+    /// gzipped and NDJSON [streamed batches](crate::ServerConfig::stream_batches) too.
+    async fn post(
+        &self,
+        call: Call<'_>,
+        body: Vec<u8>,
+        headers: &[(&'static str, String)],
+        extra: Option<&HeaderMap>,
+    ) -> Result<(hyper::StatusCode, Vec<u8>)> {
+        let uri: Uri = self.url.parse()?;
+        let signature = self
+            .signer
+            .as_ref()
+            .map(|s| (s.header_name(), s.sign(&body)));
+        let mut request = Request::post(uri).body(Full::from(body))?;
+        request.headers_mut().extend(header_map(headers)?);
+        if let Some(extra) = extra {
+            request.headers_mut().extend(extra.clone());
+        }
+        if let Some((name, signature)) = signature {
+            request.headers_mut().insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(&signature)?,
+            );
+        }
+        if self.msgpack {
+            let accept = HeaderValue::from_static("application/msgpack, application/json");
+            request.headers_mut().insert(header::ACCEPT, accept);
+        }
+        let gzip = HeaderValue::from_static("gzip");
+        request.headers_mut().insert(header::ACCEPT_ENCODING, gzip);
+
+        let start = Instant::now();
+        let sent = send_request_parts(&self.client, request, None).await;
+        if self.log_calls {
+            match &sent {
+                Ok((parts, _)) => log::debug!(
+                    "Call {} to {} returned {} in {:?}",
+                    call,
+                    self.url,
+                    parts.status,
+                    start.elapsed()
+                ),
+                Err(e) => log::debug!(
+                    "Call {} to {} failed in {:?}: {}",
+                    call,
+                    self.url,
+                    start.elapsed(),
+                    e
+                ),
+            }
+        }
+        let (parts, mut body) = sent?;
+        let headers = &parts.headers;
+        if headers
+            .get(header::CONTENT_ENCODING)
+            .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"gzip"))
+        {
+            let mut decoded = Vec::new();
+            GzDecoder::new(body.as_slice()).read_to_end(&mut decoded)?;
+            body = decoded;
+        }
+        let content_type = headers.get(header::CONTENT_TYPE).map(HeaderValue::as_bytes);
+        if content_type.is_some_and(|v| v.starts_with(MSGPACK.as_bytes())) {
+            let v: Value = rmp_serde::from_slice(&body)?;
+            return Ok((parts.status, serde_json::to_vec(&v)?));
+        }
+        if content_type.is_some_and(|v| v.starts_with(b"application/x-ndjson")) {
+            let lines = body
+                .split(|&b| b == b'\n')
+                .filter(|line| !line.trim_ascii().is_empty())
+                .map(serde_json::from_slice)
+                .collect::<serde_json::Result<Vec<Value>>>()?;
+            return Ok((parts.status, serde_json::to_vec(&lines)?));
+        }
+        Ok((parts.status, body))
+    }
+}
+
+/// Whether `e` is a failure to connect, before anything was sent.
+fn not_connected(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<hyper_util::client::legacy::Error>()
+        .is_some_and(hyper_util::client::legacy::Error::is_connect)
+}
+
+/// What a posted body holds, for [`RpcClientBuilder::log_calls`].
+enum Call<'a> {
+    Single(&'a str, &'a RpcId),
+    Batch(usize),
+}
+
+impl fmt::Display for Call<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Single(method, id) => write!(f, "{} id={}", method, id),
+            Self::Batch(n) => write!(f, "batch of {}", n),
+        }
+    }
+}
+
+/// Resolves hosts through the system, or to one fixed address.
+#[derive(Clone)]
+enum Resolver {
+    System(GaiResolver),
+    Fixed(IpAddr),
+}
+
+impl tower_service::Service<Name> for Resolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, io::Result<Self::Response>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self {
+            Self::System(r) => r.poll_ready(cx),
+            Self::Fixed(_) => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        match self {
+            Self::System(r) => r
+                .call(name)
+                .map(|addrs| Ok(addrs?.collect::<Vec<_>>().into_iter()))
+                .boxed(),
+            // Port 0 is replaced with the url's port.
+            Self::Fixed(ip) => {
+                futures::future::ready(Ok(vec![SocketAddr::new(*ip, 0)].into_iter())).boxed()
+            }
+        }
+    }
+}
+
+/// Parses an `http` or `https` URL with a host.
+pub fn parse_endpoint(url: &str) -> Result<Uri> {
+    let uri: Uri = url
+        .parse()
+        .map_err(|e| anyhow!("invalid url {:?}: {}", url, e))?;
+    match uri.scheme_str() {
+        Some("http" | "https") => {}
+        Some(s) => return Err(anyhow!("unsupported scheme {:?} in {:?}", s, url)),
+        None => return Err(anyhow!("missing http:// or https:// in {:?}", url)),
+    }
+    if uri.host().is_none_or(str::is_empty) {
+        return Err(anyhow!("missing host in {:?}", url));
+    }
+    Ok(uri)
+}
+
+/// Parses a list of endpoints separated by commas or whitespace, newlines included,
+/// skipping blank entries. Errors name the first bad entry and its 1-based position.
+pub fn parse_endpoints(s: &str) -> Result<Vec<Uri>> {
+    let uris = s
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|e| !e.is_empty())
+        .enumerate()
+        .map(|(i, e)| parse_endpoint(e).map_err(|err| anyhow!("endpoint {}: {}", i + 1, err)))
+        .collect::<Result<Vec<_>>>()?;
+    if uris.is_empty() {
+        return Err(anyhow!("no endpoints in {:?}", s));
+    }
+    Ok(uris)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::Ordering, time::Duration};
+
+    use hyper::StatusCode;
+
+    use super::*;
+    use crate::{testing, ServerConfig};
+
+    #[tokio::test]
+    async fn only_idempotent_methods_are_retried_on_5xx() {
+        let (url, hits) = testing::respond_with(StatusCode::SERVICE_UNAVAILABLE, "").await;
+        let client = RpcClient::builder()
+            .url(&url)
+            .retry(RetryPolicy {
+                base_delay: Duration::from_millis(1),
+                ..Default::default()
+            })
+            .idempotent(["get"])
+            .build()
+            .unwrap();
+
+        let r: RPCResult<Option<Value>> = client.call("transfer", &()).await;
+        assert!(r.is_err());
+        assert_eq!(hits.swap(0, Ordering::SeqCst), 1);
+
+        let r: RPCResult<Option<Value>> = client.call("get", &()).await;
+        assert!(r.is_err());
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn parses_endpoint_lists() {
+        let uris = parse_endpoints("http://a:1,https://b/rpc\n http://c ,, \n\thttp://d").unwrap();
+        let hosts: Vec<_> = uris.iter().map(|u| u.host().unwrap()).collect();
+        assert_eq!(hosts, ["a", "b", "c", "d"]);
+        assert_eq!(uris[1].path(), "/rpc");
+
+        let e = parse_endpoints("http://a, ftp://b")
+            .unwrap_err()
+            .to_string();
+        assert!(e.starts_with("endpoint 2: unsupported scheme"), "{e}");
+        let e = parse_endpoints("http://a,,b.example")
+            .unwrap_err()
+            .to_string();
+        assert!(e.starts_with("endpoint 2: missing http://"), "{e}");
+        let e = parse_endpoints("http://a http://[::1")
+            .unwrap_err()
+            .to_string();
+        assert!(e.starts_with("endpoint 2: invalid url"), "{e}");
+        assert!(parse_endpoints(" ,\n ").is_err());
+    }
+
+    #[tokio::test]
+    async fn gzipped_streamed_batches_are_decoded() {
+        let addr = testing::serve(ServerConfig {
+            stream_batches: true,
+            gzip_streamed_batches: true,
+            ..Default::default()
+        })
+        .await;
+        let url = format!("http://{addr}");
+        let requests: Vec<_> = (1..=3)
+            .map(|id| RPCRequest {
+                id: Some(RpcId::from(id)),
+                ..RPCRequest::new("echo", vec![id])
+            })
+            .collect();
+
+        // The server does gzip it for the client, which asks for it.
+        let body = serde_json::to_vec(&requests).unwrap();
+        let headers = [("Accept-Encoding", String::from("gzip"))];
+        let (_, raw) = crate::http_post(&url, &body, Some(&headers)).await.unwrap();
+        assert_eq!(raw[..2], [0x1f, 0x8b]);
+
+        let client = RpcClient::new(&url).unwrap();
+        let responses: Vec<RPCResponse<Vec<i64>>> = client.batch_call(&requests).await.unwrap();
+        let mut results: Vec<_> = responses
+            .into_iter()
+            .map(|r| (r.id.clone(), r.try_into_result().unwrap()))
+            .collect();
+        results.sort_by_key(|(_, r)| r[0]);
+        let expected: Vec<_> = (1..=3).map(|id| (RpcId::from(id), vec![id])).collect();
+        assert_eq!(results, expected);
+    }
+
+    #[tokio::test]
+    async fn logs_calls_when_asked_to() {
+        testing::logs();
+        let addr = testing::serve(Default::default()).await;
+        let url = format!("http://{addr}");
+        let quiet = RpcClient::new(&url).unwrap();
+        let _: Option<Value> = quiet.call("echo", &[1]).await.unwrap();
+
+        let client = RpcClient::builder()
+            .url(&url)
+            .log_calls(true)
+            .build()
+            .unwrap();
+        let _: Option<Value> = client.call("echo", &[1]).await.unwrap();
+        // Both calls have id 1, only the second is logged.
+        let lines = testing::logged(&format!("Call echo id=1 to {url} returned 200"));
+        assert_eq!(lines.len(), 1, "{lines:?}");
+        assert_eq!(lines[0].0, log::Level::Debug);
+    }
+}
@@ -1,22 +1,56 @@
-use std::{fmt::Debug, future::Future, net::SocketAddr, pin::Pin, sync::Arc};
+use std::{
+    convert::Infallible,
+    fmt::Debug,
+    future::Future,
+    io::{self, Write},
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use async_trait::async_trait;
 use bytes::Bytes;
-use http_body_util::{BodyExt, Full};
+use flate2::{write::GzEncoder, Compression};
+use futures::{
+    future::{self, BoxFuture},
+    stream, Stream, StreamExt, TryStreamExt,
+};
+use http_body_util::{
+    combinators::UnsyncBoxBody, BodyExt, Full, LengthLimitError, Limited, StreamBody,
+};
 use hyper::{
-    body::Incoming,
-    server::conn::http1,
+    body::{Body, Frame, Incoming},
+    header::{self, HeaderValue},
     service::{service_fn, Service},
-    Request, Response,
+    Method, Request, Response, StatusCode,
 };
 use hyper_util::rt::TokioIo;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use tokio::net::TcpListener;
+use serde_json::{value::RawValue, Value};
+use socket2::{Domain, Socket, Type};
+use tokio::{net::TcpListener, sync::watch, task::JoinHandle};
+use tracing::Instrument;
 
-use crate::{RPCError, RPCRequest, RPCResponse};
+use crate::{
+    codec::MSGPACK,
+    json::{self, Projection, Shape},
+    redirect, validate_request, ws, CallLatencies, Codec, FollowUpSink, RPCError, RPCRequest,
+    RPCResponse, RPCResult, RequestContext, ResponsePayload, RpcId, ServerConfig, ServerEvent,
+    SubscriptionSink, TraceContext,
+};
 
+/// Dispatches calls by method name.
+///
+/// The request envelope is parsed first with `params` kept as raw JSON, which is then
+/// converted into [`Handle::Request`]. If that conversion fails only this request is
+/// answered with `-32602`, the handler never sees it. Services whose methods take
+/// unrelated param shapes can use `type Request = serde_json::Value` and convert per
+/// method with [`parse_params`] instead of a catch-all untagged enum.
 #[async_trait]
 pub trait Handle {
     type Request: for<'de> Deserialize<'de> + Send + Sync + Clone + 'static;
@@ -28,129 +62,2906 @@ pub trait Handle {
         req: Option<Self::Request>,
     ) -> std::result::Result<Option<Self::Response>, RPCError>;
 
+    /// Most entries of one batch the server runs at a time.
+    const BATCH_CONCURRENCY: usize = 16;
+
+    /// Runs the entries concurrently, answering the calls in request order and none of
+    /// the notifications.
+    ///
+    /// The server doesn't call this: it answers each batch entry through
+    /// [`Handle::handle`] like a single call, so entries get the same retries, timeouts,
+    /// latencies and events. It is kept for running batches outside of a server.
     async fn batch_handle(
         &self,
         reqests: Vec<RPCRequest<Option<Self::Request>>>,
     ) -> Vec<RPCResponse<Self::Response>> {
-        let mut response = vec![];
-        for reqest in reqests {
-            let resp = self
-                .handle(&reqest.method, reqest.params)
-                .await
-                .map_or_else(
-                    |e| RPCResponse::error(reqest.id.clone(), e),
-                    |v| RPCResponse::result(reqest.id.clone(), v),
+        stream::iter(reqests)
+            .map(|reqest| async move {
+                let resp = self.handle(&reqest.method, reqest.params).await;
+                reqest.id.map(|id| RPCResponse::from_result(id, resp))
+            })
+            .buffered(Self::BATCH_CONCURRENCY.max(1))
+            .filter_map(future::ready)
+            .collect()
+            .await
+    }
+
+    /// Whether `method` opens a WebSocket subscription, see [`Handle::subscribe`].
+    fn is_subscription(&self, _method: &str) -> bool {
+        false
+    }
+
+    /// Starts the subscription for `method`. The returned future pushes notifications
+    /// through `sink` and is cancelled on `rpc.unsubscribe` or when the connection closes.
+    async fn subscribe(
+        &self,
+        _method: &str,
+        _req: Option<Self::Request>,
+        _sink: SubscriptionSink,
+    ) -> RPCResult<BoxFuture<'static, ()>> {
+        Err(RPCError::unknown_method())
+    }
+
+    /// Whether `method` sends follow-ups after its result over WebSocket, see
+    /// [`Handle::handle_with_follow_ups`]. Over HTTP it is answered by [`Handle::handle`].
+    fn has_follow_ups(&self, _method: &str) -> bool {
+        false
+    }
+
+    /// Answers `method` right away and returns a future that keeps sending follow-up
+    /// notifications through `sink`, each tagged with the id of this request. The
+    /// future starts once the result is sent and is cancelled when the connection closes.
+    async fn handle_with_follow_ups(
+        &self,
+        _method: &str,
+        _req: Option<Self::Request>,
+        _sink: FollowUpSink,
+    ) -> RPCResult<(Option<Self::Response>, BoxFuture<'static, ()>)> {
+        Err(RPCError::unknown_method())
+    }
+}
+
+/// Converts raw params into the type one method expects, answering `-32602` on mismatch.
+pub fn parse_params<T>(params: Option<Value>) -> RPCResult<T>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    serde_json::from_value(params.unwrap_or(Value::Null)).map_err(|e| {
+        log::debug!("Invalid params: {}", e);
+        params_error(&e)
+    })
+}
+
+/// `-32602` with serde's reason in `data`, e.g. the out-of-range integer or, for
+/// untagged enums, that the params matched none of the variants.
+fn params_error(e: &serde_json::Error) -> RPCError {
+    RPCError {
+        data: Some(e.to_string()),
+        ..RPCError::invalid_params()
+    }
+}
+
+/// Checks run on the raw request before it is deserialized and dispatched.
+fn precheck(req: &Value, config: &ServerConfig) -> RPCResult<()> {
+    if !req.is_object() {
+        return Err(RPCError::invalid_request());
+    }
+    validate_request(req)?;
+    if config.strict_members {
+        let extra = req
+            .as_object()
+            .into_iter()
+            .flatten()
+            .find(|(k, _)| !matches!(k.as_str(), "jsonrpc" | "method" | "params" | "id"));
+        if let Some((k, _)) = extra {
+            return Err(RPCError {
+                data: Some(format!("unexpected member {:?}", k)),
+                ..RPCError::invalid_request()
+            });
+        }
+    }
+    if config.reject_fractional_ids && req.get("id").is_some_and(Value::is_f64) {
+        return Err(fractional_id());
+    }
+    let method = req
+        .get("method")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    let params = req.get("params").unwrap_or(&Value::Null);
+    config.check_no_params(method, params)?;
+    config.validate_params(method, params)
+}
+
+pub(crate) fn request_id(req: &Value) -> RpcId {
+    req.get("id")
+        .and_then(|v| RpcId::deserialize(v).ok())
+        .unwrap_or_default()
+}
+
+/// A request ready for its handler, or the response rejecting it.
+type Parsed<H> = std::result::Result<
+    RPCRequest<Option<<H as Handle>::Request>>,
+    RPCResponse<<H as Handle>::Response>,
+>;
+
+fn fractional_id() -> RPCError {
+    RPCError {
+        data: Some(String::from("id must be an integer")),
+        ..RPCError::invalid_request()
+    }
+}
+
+/// Deserializes one request, turning rejections into the response to send back instead.
+fn parse_request<H>(req: Value, config: &ServerConfig) -> Parsed<H>
+where
+    H: Handle,
+{
+    let id = match request_id(&req) {
+        // An id rejected as invalid is answered with null, as if it couldn't be read.
+        RpcId::Fractional(_) if config.reject_fractional_ids => RpcId::Null,
+        id => id,
+    };
+    if let Err(e) = precheck(&req, config) {
+        return Err(RPCResponse::error(id, e));
+    }
+    let req: RPCRequest<Option<Value>> = serde_json::from_value(req).map_err(|e| {
+        log::debug!("Invalid request: {}", e);
+        RPCResponse::error(id, RPCError::invalid_request())
+    })?;
+
+    let params = match req.params {
+        Some(v) => match serde_json::from_value(v) {
+            Ok(p) => Some(p),
+            Err(e) => {
+                log::debug!("Invalid params for {}: {}", req.method, e);
+                return Err(RPCResponse::error(
+                    req.id.unwrap_or_default(),
+                    params_error(&e),
+                ));
+            }
+        },
+        None => None,
+    };
+    Ok(RPCRequest {
+        jsonrpc: req.jsonrpc,
+        method: req.method,
+        params,
+        id: req.id,
+    })
+}
+
+/// Parses a single request straight from `body` into [`Handle::Request`], so large params
+/// are never built into a [`Value`] first. `None` leaves the request to [`_handle`], when
+/// a feature needs the `Value` or to report what the request got wrong.
+fn parse_direct<H>(body: &[u8], config: &ServerConfig) -> Option<Parsed<H>>
+where
+    H: Handle,
+{
+    let needs_value = config.log_bodies
+        || config.request_transform.is_some()
+        || !config.param_schemas.is_empty()
+        || config.strict_members;
+    if needs_value {
+        return None;
+    }
+
+    let req: RPCRequest<Option<&RawValue>> = serde_json::from_slice(body).ok()?;
+    if req.jsonrpc != "2.0" {
+        return None;
+    }
+    if config.reject_fractional_ids && matches!(req.id, Some(RpcId::Fractional(_))) {
+        return Some(Err(RPCResponse::error(RpcId::Null, fractional_id())));
+    }
+    if let Some(raw) = req
+        .params
+        .filter(|_| config.no_params.contains(&req.method))
+    {
+        let params = serde_json::from_str(raw.get()).unwrap_or_default();
+        if let Err(e) = config.check_no_params(&req.method, &params) {
+            return Some(Err(RPCResponse::error(req.id.unwrap_or_default(), e)));
+        }
+    }
+    let params = match req.params {
+        None => None,
+        Some(raw) if raw.get().starts_with(['[', '{']) => match serde_json::from_str(raw.get()) {
+            Ok(p) => Some(p),
+            Err(e) => {
+                log::debug!("Invalid params for {}: {}", req.method, e);
+                return Some(Err(RPCResponse::error(
+                    req.id.unwrap_or_default(),
+                    params_error(&e),
+                )));
+            }
+        },
+        Some(_) => return None,
+    };
+    Some(Ok(RPCRequest {
+        jsonrpc: req.jsonrpc,
+        method: req.method,
+        params,
+        id: req.id,
+    }))
+}
+
+async fn call_handler<H>(
+    handle: &H,
+    config: &ServerConfig,
+    method: &str,
+    mut params: Option<H::Request>,
+) -> RPCResult<Option<H::Response>>
+where
+    H: Handle,
+{
+    let Some(retry) = config.handler_retries.get(method) else {
+        return handle.handle(method, params).await;
+    };
+
+    let mut attempt = 1;
+    loop {
+        // Only attempts that may be retried need their own copy of the params.
+        let p = if attempt < retry.max_attempts {
+            params.clone()
+        } else {
+            params.take()
+        };
+        match handle.handle(method, p).await {
+            Err(e) if attempt < retry.max_attempts && retry.codes.contains(&e.code) => {
+                let delay = retry.delay(attempt);
+                log::debug!(
+                    "Retrying {} after error {} in {:?}, attempt {}",
+                    method,
+                    e.code,
+                    delay,
+                    attempt
                 );
-            response.push(resp);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            r => return r,
         }
-        response
     }
 }
 
-async fn _handle<H>(req_body: serde_json::Value, handle: &H) -> Result<serde_json::Value>
+/// The encoded response to one request, `None` for a notification. Requests that can't
+/// be read are answered whether or not they carry an id.
+pub(crate) async fn _handle<H>(
+    req_body: serde_json::Value,
+    handle: &H,
+    config: &ServerConfig,
+) -> Result<Option<String>>
+where
+    H: Handle,
+    H::Request: Debug,
+{
+    match parse_request::<H>(req_body, config) {
+        Ok(req) => answer(req, handle, config).await,
+        Err(resp) => encode(resp, config).map(Some),
+    }
+}
+
+/// Calls the handler of a parsed request and encodes its response, if it is a call.
+async fn answer<H>(
+    req: RPCRequest<Option<H::Request>>,
+    handle: &H,
+    config: &ServerConfig,
+) -> Result<Option<String>>
+where
+    H: Handle,
+    H::Request: Debug,
+{
+    respond(req, handle, config)
+        .await
+        .map(|resp| encode(resp, config))
+        .transpose()
+}
+
+/// What a call returned, from its handler or [`ServerConfig::method_overrides`].
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Outcome<T> {
+    Handled(T),
+    Canned(Value),
+}
+
+/// A response built without calling the handler, e.g. rejecting the request.
+fn unhandled<T>(resp: RPCResponse<T>) -> RPCResponse<Outcome<T>> {
+    let payload = match resp.payload {
+        ResponsePayload::Result(r) => ResponsePayload::Result(r.map(Outcome::Handled)),
+        ResponsePayload::Error(e) => ResponsePayload::Error(e),
+    };
+    RPCResponse {
+        jsonrpc: resp.jsonrpc,
+        payload,
+        id: resp.id,
+    }
+}
+
+/// Calls the handler of a parsed request, returning its response unless it is a
+/// notification, which the spec says is never answered, not even with an error.
+async fn respond<H>(
+    req: RPCRequest<Option<H::Request>>,
+    handle: &H,
+    config: &ServerConfig,
+) -> Option<RPCResponse<Outcome<H::Response>>>
 where
     H: Handle,
     H::Request: Debug,
 {
-    let req: RPCRequest<Option<H::Request>> = serde_json::from_value(req_body)?;
+    if !config.method_allowed(&req.method) {
+        warn_unknown(&req.method);
+        return req
+            .id
+            .map(|id| RPCResponse::error(id, RPCError::unknown_method()));
+    }
+
+    if let Err(e) = authorize(config, &req.method).await {
+        return req.id.map(|id| RPCResponse::error(id, e));
+    }
+
+    if let Some(w) = config.deprecation(&req.method) {
+        log::warn!("{}", w);
+    }
+
+    let level = config.call_log_level;
+    match TraceContext::current() {
+        Some(ctx) => log::log!(
+            level,
+            "Get call method: {} trace_id={}",
+            &req.method,
+            ctx.trace_id
+        ),
+        None => log::log!(level, "Get call method: {}", &req.method),
+    }
+
+    config.emit(|| ServerEvent::Dispatched {
+        method: req.method.clone(),
+        peer: RequestContext::current().map(|c| c.peer),
+    });
+    let started = Instant::now();
+    // A canned response stands in for the handler only, the call is checked,
+    // authorized and accounted for like any other.
+    let r = match config.method_overrides.get(&req.method) {
+        Some(canned) => {
+            log::log!(
+                level,
+                "Call method {} answered by its override",
+                &req.method
+            );
+            canned.clone().map(|v| Some(Outcome::Canned(v)))
+        }
+        None => {
+            let r = call_handler(handle, config, &req.method, req.params);
+            let r = match config.timeout(&req.method) {
+                Some(limit) => tokio::time::timeout(limit, r).await.unwrap_or_else(|_| {
+                    log::warn!("Call method {} timed out after {:?}", &req.method, limit);
+                    Err(RPCError::request_timeout())
+                }),
+                None => r.await,
+            };
+            r.map(|r| r.map(Outcome::Handled))
+        }
+    };
+    let r = match r {
+        Ok(None) if req.id.is_some() && config.non_empty_results.contains(&req.method) => {
+            Err(empty_result(&req.method))
+        }
+        Err(e) if e.code == UNKNOWN_METHOD => {
+            warn_unknown(&req.method);
+            Err(e)
+        }
+        r => r,
+    };
+    let elapsed = started.elapsed();
+    // Unknown methods would otherwise each get a histogram of their own.
+    let known = !matches!(&r, Err(e) if e.code == UNKNOWN_METHOD);
+    if let Some(latencies) = config.latencies.as_ref().filter(|_| known) {
+        latencies.record(&req.method, elapsed);
+    }
+    if config.slow_call.is_some_and(|limit| elapsed > limit) {
+        config.emit(|| ServerEvent::Slow {
+            method: req.method.clone(),
+            elapsed_ms: elapsed.as_millis() as u64,
+        });
+    }
+    if let Err(e) = &r {
+        config.emit(|| failed(&req.method, e));
+    }
+
+    req.id.map(|id| RPCResponse::from_result(id, r))
+}
+
+/// Asks [`ServerConfig::authorizer`] about calling `method` in the current request,
+/// denying calls made outside of one.
+pub(crate) async fn authorize(config: &ServerConfig, method: &str) -> RPCResult<()> {
+    let Some(authorizer) = &config.authorizer else {
+        return Ok(());
+    };
+    let Some(ctx) = RequestContext::current() else {
+        return Err(RPCError::forbidden());
+    };
+    authorizer.authorize(method, &ctx).await.inspect_err(|e| {
+        log::info!(
+            "Denied call method {} to {}: {}",
+            method,
+            ctx.peer,
+            e.message
+        )
+    })
+}
+
+const UNKNOWN_METHOD: i32 = -32601;
+
+/// Calls to unknown methods may be someone probing, so they stand out from other calls.
+fn warn_unknown(method: &str) {
+    match RequestContext::current() {
+        Some(ctx) => log::warn!("Call to unknown method {} from {}", method, ctx.peer),
+        None => log::warn!("Call to unknown method {}", method),
+    }
+}
+
+fn failed(method: &str, e: &RPCError) -> ServerEvent {
+    ServerEvent::Failed {
+        method: String::from(method),
+        code: e.code,
+        message: e.message.clone(),
+    }
+}
+
+/// Answers a call to one of [`ServerConfig::non_empty_results`] that returned `Ok(None)`.
+fn empty_result(method: &str) -> RPCError {
+    log::error!("Method {} returned an empty result", method);
+    RPCError::internal_error(String::from("unexpected empty result"))
+}
 
-    log::info!("Get call method: {}", &req.method);
-    log::debug!("Params is: {:?}", &req.params);
+/// Serializes every response the server sends, as [`ServerConfig`] asks for.
+pub(crate) fn encode<T: Serialize>(resp: RPCResponse<T>, config: &ServerConfig) -> Result<String> {
+    if config.max_response_bytes.is_none() && config.max_response_depth.is_none() {
+        return serialize(resp, config);
+    }
+    let id = resp.id.clone();
+    let body = serialize(resp, config)?;
+    let e = if let Some(limit) = config.max_response_bytes.filter(|l| body.len() > *l) {
+        log::warn!(
+            "Dropping response of {} bytes to {}, over the limit of {}",
+            body.len(),
+            id,
+            limit
+        );
+        RPCError::response_too_large()
+    } else if let Some(limit) = config
+        .max_response_depth
+        .filter(|l| json::exceeds_depth(body.as_bytes(), *l))
+    {
+        log::warn!("Dropping response to {}, nested deeper than {}", id, limit);
+        RPCError::internal_error(String::from("response nested too deeply"))
+    } else {
+        return Ok(body);
+    };
+    serialize(RPCResponse::<()>::error(id, e), config)
+}
 
-    let r = match handle.handle(&req.method, req.params).await {
-        Ok(v) => RPCResponse::result(req.id, v),
-        Err(e) => RPCResponse::error(req.id, e),
+/// Like [`encode`], straight into `codec`'s format. Only [`ServerConfig::max_response_bytes`]
+/// is enforced, see [`encodes_directly`].
+fn encode_as<T: Serialize>(
+    resp: RPCResponse<T>,
+    config: &ServerConfig,
+    codec: &dyn Codec,
+) -> Result<Vec<u8>> {
+    let id = resp.id.clone();
+    let body = match config.canonical_json {
+        true => serialize_as(&resp.canonical()?, config, codec)?,
+        false => serialize_as(&resp, config, codec)?,
     };
+    match config.max_response_bytes {
+        Some(limit) if body.len() > limit => {
+            log::warn!(
+                "Dropping response of {} bytes to {}, over the limit of {}",
+                body.len(),
+                id,
+                limit
+            );
+            let e = RPCResponse::<()>::error(id, RPCError::response_too_large());
+            serialize_as(&e, config, codec)
+        }
+        _ => Ok(body),
+    }
+}
+
+/// MessagePack is written from the response itself; other codecs get it as a [`Value`].
+fn serialize_as<T: Serialize>(
+    resp: &RPCResponse<T>,
+    config: &ServerConfig,
+    codec: &dyn Codec,
+) -> Result<Vec<u8>> {
+    let msgpack = codec.content_type() == MSGPACK;
+    match (msgpack, config.always_error_data) {
+        (true, true) => Ok(rmp_serde::to_vec_named(&resp.with_error_data())?),
+        (true, false) => Ok(rmp_serde::to_vec_named(resp)?),
+        (false, true) => codec.encode_response(&serde_json::to_value(resp.with_error_data())?),
+        (false, false) => codec.encode_response(&serde_json::to_value(resp)?),
+    }
+}
 
-    r.into_value()
+/// Whether a single call's response can skip being JSON, which response transforms,
+/// body logging and the depth limit all work on.
+fn encodes_directly(config: &ServerConfig) -> bool {
+    config.response_transform.is_none() && !config.log_bodies && config.max_response_depth.is_none()
 }
-async fn _batch_handle<H>(req_body: serde_json::Value, handle: &H) -> Result<serde_json::Value>
+
+fn serialize<T: Serialize>(resp: RPCResponse<T>, config: &ServerConfig) -> Result<String> {
+    match (config.canonical_json, config.always_error_data) {
+        (true, true) => resp.canonical()?.into_json_with_error_data(),
+        (true, false) => resp.canonical()?.into_json(),
+        (false, true) => resp.into_json_with_error_data(),
+        (false, false) => resp.into_json(),
+    }
+}
+
+pub(crate) async fn _batch_handle<H>(
+    req_body: serde_json::Value,
+    handle: &H,
+    config: &ServerConfig,
+) -> Result<Option<String>>
 where
     H: Handle + Sync,
     H::Request: Debug,
 {
-    let req: Vec<RPCRequest<Option<H::Request>>> = serde_json::from_value(req_body)?;
+    // An empty batch is answered with one error, not an empty array.
+    if let Err(e) = validate_request(&req_body) {
+        return encode(RPCResponse::<()>::error(RpcId::Null, e), config).map(Some);
+    }
+    let _permit = match config.batch_permit() {
+        Ok(p) => p,
+        Err(e) => return encode(RPCResponse::<()>::error(RpcId::Null, e), config).map(Some),
+    };
+    let Value::Array(entries) = req_body else {
+        anyhow::bail!("Batch is not an array");
+    };
+
+    log::info!("Get batch call with {} requests", entries.len());
+
+    // Each entry is answered like a single call, so it is retried, timed out and
+    // measured the same way.
+    let r = stream::iter(entries)
+        .map(|entry| _handle(entry, handle, config))
+        .buffered(H::BATCH_CONCURRENCY.max(1))
+        .try_filter_map(future::ok)
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    // A batch of notifications only gets no response at all, not `[]`.
+    Ok((!r.is_empty()).then(|| format!("[{}]", r.join(","))))
+}
+
+/// Runtime state shared by every connection of one server.
+#[derive(Debug, Default)]
+pub(crate) struct ServerState {
+    in_flight: AtomicUsize,
+    maintenance: AtomicBool,
+    draining: AtomicBool,
+    connections: AtomicUsize,
+}
+
+impl ServerState {
+    /// Why a request arriving with `current` requests in flight, itself included, is
+    /// turned away with `server_busy`, if it is.
+    pub(crate) fn refusal(&self, config: &ServerConfig, current: usize) -> Option<RPCError> {
+        if self.draining.load(Ordering::SeqCst) {
+            return Some(RPCError::server_busy().with_message("Server shutting down"));
+        }
+        if self.maintenance.load(Ordering::SeqCst) {
+            return Some(RPCError::server_busy().with_message("Server under maintenance"));
+        }
+        match config.max_in_flight {
+            Some(max) if current > max => {
+                log::warn!("Shedding request, {} requests in flight", current - 1);
+                Some(RPCError::server_busy())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Counts one request as in flight until dropped, so errors, panics and requests
+/// cancelled by a disconnecting client are always uncounted again.
+pub(crate) struct InFlightGuard {
+    state: Arc<ServerState>,
+    pub(crate) current: usize,
+    done: bool,
+}
+
+impl InFlightGuard {
+    pub(crate) fn new(state: Arc<ServerState>) -> Self {
+        let current = state.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        Self {
+            state,
+            current,
+            done: false,
+        }
+    }
+
+    pub(crate) fn finish(mut self) {
+        self.done = true;
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if !self.done {
+            log::debug!("Client went away, cancelled its in-flight request");
+        }
+        self.state.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Whether serving a connection failed only because the client hung up early.
+fn client_gone(err: &hyper::Error) -> bool {
+    if err.is_incomplete_message() || err.is_canceled() || err.is_closed() {
+        return true;
+    }
+    let mut source = std::error::Error::source(err);
+    while let Some(e) = source {
+        if let Some(io) = e.downcast_ref::<std::io::Error>() {
+            return matches!(
+                io.kind(),
+                std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::UnexpectedEof
+            );
+        }
+        source = e.source();
+    }
+    false
+}
 
-    log::debug!("Batch params is: {:?}", &req);
+/// Counts one open connection until dropped.
+struct ConnectionGuard(Arc<ServerState>);
 
-    let r = handle.batch_handle(req).await;
+impl ConnectionGuard {
+    fn new(state: Arc<ServerState>) -> Self {
+        state.connections.fetch_add(1, Ordering::SeqCst);
+        Self(state)
+    }
+}
 
-    Ok(serde_json::to_value(r)?)
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 struct HandleHttp<H> {
     handle: Arc<H>,
+    config: Arc<ServerConfig>,
+    state: Arc<ServerState>,
+    peer: SocketAddr,
 }
 
-impl<H> Service<Request<Incoming>> for HandleHttp<H>
+/// Body of every HTTP response, either fully buffered or streamed.
+pub type ResponseBody = UnsyncBoxBody<Bytes, Infallible>;
+
+/// A buffered [`ResponseBody`], e.g. for a [`NotFoundHandler`](crate::NotFoundHandler).
+pub fn full_body(body: impl Into<Bytes>) -> ResponseBody {
+    Full::new(body.into()).boxed_unsync()
+}
+
+fn error_response(
+    config: &ServerConfig,
+    status: StatusCode,
+    e: RPCError,
+) -> Result<Response<ResponseBody>> {
+    let body = encode(RPCResponse::<()>::error(RpcId::Null, e), config)?;
+    Ok(Response::builder()
+        .status(status)
+        .header("Content-Type", &config.content_type)
+        .body(full_body(body))?)
+}
+
+/// Answers a request made of notifications only, which get no response of any kind.
+fn no_content() -> Result<Response<ResponseBody>> {
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(full_body(Bytes::new()))?)
+}
+
+fn redirect_response(r: redirect::Redirect) -> Result<Response<ResponseBody>> {
+    Ok(Response::builder()
+        .status(r.status)
+        .header(header::LOCATION, r.location)
+        .body(full_body(Bytes::new()))?)
+}
+
+fn body_too_large() -> RPCError {
+    RPCError::invalid_request().with_message("Request body too large")
+}
+
+/// Writes each batch entry as one NDJSON line as soon as its handler resolves,
+/// gzipped line by line if `gzip`, running up to [`Handle::BATCH_CONCURRENCY`] at once.
+fn stream_batch<H>(
+    req_body: Value,
+    handle: Arc<H>,
+    config: Arc<ServerConfig>,
+    gzip: bool,
+) -> Result<Response<ResponseBody>>
 where
     H: Handle + Send + Sync + 'static,
     H::Request: Debug,
 {
-    type Response = Response<Full<Bytes>>;
-    type Error = anyhow::Error;
-    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
-
-    fn call(&self, request: Request<Incoming>) -> Self::Future {
-        let handle = self.handle.clone();
+    let permit = match config.batch_permit() {
+        Ok(p) => p,
+        Err(e) => return error_response(&config, StatusCode::SERVICE_UNAVAILABLE, e),
+    };
+    let Value::Array(entries) = req_body else {
+        anyhow::bail!("Batch is not an array");
+    };
+    log::info!("Get streamed batch call with {} requests", entries.len());
 
-        let r = async move {
-            let req_body = request
-                .into_body()
-                .collect()
-                .await
-                .map_err(|e| anyhow!("{e}"))?
-                .to_bytes()
-                .to_vec();
-
-            log::debug!("Request Body: {:?}", req_body);
-
-            let req_body = serde_json::from_slice::<Value>(&req_body)?;
-
-            let body = if req_body.is_object() {
-                let r = _handle(req_body, handle.as_ref()).await?;
-                serde_json::to_string(&r)?
-            } else if req_body.is_array() {
-                let r = _batch_handle(req_body, handle.as_ref()).await?;
-                serde_json::to_string(&r)?
-            } else {
-                return Err(anyhow!("Unsupport type"));
-            };
-            log::debug!("Response Body: {:?}", body);
+    let ctx = TraceContext::current();
+    let request_ctx = RequestContext::current();
+    let concurrency = H::BATCH_CONCURRENCY.max(1);
+    let pending = stream::iter(entries)
+        .map(move |entry| {
+            let (handle, config) = (handle.clone(), config.clone());
+            let (ctx, request_ctx) = (ctx.clone(), request_ctx.clone());
+            async move {
+                let id = request_id(&entry);
+                let r = _handle(entry, handle.as_ref(), &config);
+                let r = async {
+                    match ctx {
+                        Some(ctx) => ctx.scope(r).await,
+                        None => r.await,
+                    }
+                };
+                let r = match request_ctx {
+                    Some(request_ctx) => request_ctx.scope(r).await,
+                    None => r.await,
+                };
+                let mut line = r.unwrap_or_else(|e| {
+                    log::debug!("Invalid batch entry: {:?}", e);
+                    let e = RPCResponse::<()>::error(id, RPCError::invalid_request());
+                    Some(encode(e, &config).unwrap_or_default())
+                })?;
+                line.push('\n');
+                Some(Bytes::from(line))
+            }
+        })
+        .buffer_unordered(concurrency)
+        .filter_map(future::ready);
+    let lines = if gzip {
+        gzip_lines(pending).boxed()
+    } else {
+        pending.boxed()
+    };
+    // Moving the permit into the body holds the batch slot until the last line is written.
+    let frames = lines.map(move |line| {
+        let _slot = &permit;
+        Ok(Frame::data(line))
+    });
 
-            let resp = Response::builder()
-                .header("Content-Type", "application/json")
-                .body(Full::new(Bytes::from(body)))?;
+    let mut builder = Response::builder().header("Content-Type", "application/x-ndjson");
+    if gzip {
+        builder = builder
+            .header(header::CONTENT_ENCODING, "gzip")
+            .header(header::VARY, "accept-encoding");
+    }
+    Ok(builder.body(StreamBody::new(frames).boxed_unsync())?)
+}
 
-            Ok(resp)
-        };
+/// Gzips `lines` as one member, flushing after each so clients can decode every
+/// line as soon as it arrives. Only one line is held at a time, and the next one
+/// is not produced until hyper has taken the last.
+fn gzip_lines(lines: impl Stream<Item = Bytes> + Send + 'static) -> impl Stream<Item = Bytes> {
+    let encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    stream::unfold(
+        (lines.boxed(), Some(encoder)),
+        |(mut lines, encoder)| async move {
+            let mut encoder = encoder?;
+            match lines.next().await {
+                Some(line) => {
+                    // Writing into a `Vec` can't fail.
+                    let _ = encoder.write_all(&line).and_then(|_| encoder.flush());
+                    let chunk = Bytes::from(std::mem::take(encoder.get_mut()));
+                    Some((chunk, (lines, Some(encoder))))
+                }
+                None => {
+                    let trailer = encoder.finish().unwrap_or_default();
+                    Some((Bytes::from(trailer), (lines, None)))
+                }
+            }
+        },
+    )
+}
 
-        Box::pin(r)
-    }
+/// Whether `Accept-Encoding` allows gzip, i.e. lists it without `q=0`.
+fn accepts_gzip(headers: &hyper::HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|coding| {
+            let mut parts = coding.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+            let refused = parts.any(|p| {
+                p.strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            name.eq_ignore_ascii_case("gzip") && !refused
+        })
 }
 
-pub async fn serve<H>(addr: &SocketAddr, handle: H) -> Result<()>
+async fn process<H, B>(
+    request: Request<B>,
+    handle: Arc<H>,
+    config: Arc<ServerConfig>,
+) -> Result<Response<ResponseBody>>
 where
     H: Handle + Send + Sync + 'static,
     H::Request: Debug,
+    B: Body<Data = Bytes>,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
 {
-    let listener = TcpListener::bind(addr).await?;
-    println!("Listening on http://{}", addr);
+    // hyper only sends `100 Continue` once the body is polled, so everything
+    // rejected before this point never makes the client upload it.
+    let limit = config.max_body_size.unwrap_or(usize::MAX);
+    let declared = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<u64>().ok());
+    if declared.is_some_and(|n| n > limit as u64) {
+        let expects_continue = request
+            .headers()
+            .get(header::EXPECT)
+            .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"100-continue"));
+        let status = if expects_continue {
+            StatusCode::EXPECTATION_FAILED
+        } else {
+            StatusCode::PAYLOAD_TOO_LARGE
+        };
+        return error_response(&config, status, body_too_large());
+    }
 
-    let handle = Arc::new(handle);
+    let request_codec = config.request_codec(request.headers());
+    let response_codec = config.response_codec(request.headers());
 
-    loop {
-        let (stream, _) = listener.accept().await?;
-        let io = TokioIo::new(stream);
+    let gzip = config.gzip_streamed_batches && accepts_gzip(request.headers());
 
-        let handle = handle.clone();
-        let service = service_fn(move |req| {
-            let value = handle.clone();
-            async move { HandleHttp { handle: value }.call(req).await }
-        });
+    let projection = match config.result_fields {
+        true => request
+            .headers()
+            .get(RESULT_FIELDS)
+            .and_then(|v| v.to_str().ok())
+            .map(Projection::parse),
+        false => None,
+    };
+
+    let signature = config.signer.as_ref().map(|signer| {
+        request
+            .headers()
+            .get(signer.header_name())
+            .and_then(|v| v.to_str().ok())
+            .map(String::from)
+    });
+
+    let req_body = match Limited::new(request.into_body(), limit).collect().await {
+        Ok(b) => b.to_bytes().to_vec(),
+        Err(e) if e.is::<LengthLimitError>() => {
+            return error_response(&config, StatusCode::PAYLOAD_TOO_LARGE, body_too_large());
+        }
+        // E.g. fewer bytes than `Content-Length` announced before the client half-closed.
+        Err(e) => {
+            log::debug!("Failed to read request body: {}", e);
+            let e = RPCError {
+                data: Some(String::from("incomplete request body")),
+                ..RPCError::parse_error()
+            };
+            return error_response(&config, StatusCode::BAD_REQUEST, e);
+        }
+    };
+
+    if let (Some(signer), Some(signature)) = (&config.signer, signature) {
+        if !signature.is_some_and(|s| signer.verify(&req_body, &s)) {
+            let e = RPCError::unauthorized().with_message("Invalid signature");
+            return error_response(&config, StatusCode::UNAUTHORIZED, e);
+        }
+    }
+
+    let decoded = match &request_codec {
+        Some(codec) => match codec.decode_request(&req_body) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                log::debug!("Failed to decode {} request: {}", codec.content_type(), e);
+                return error_response(&config, StatusCode::BAD_REQUEST, RPCError::parse_error());
+            }
+        },
+        None => None,
+    };
+
+    if decoded.is_none() && config.too_deep(&req_body) {
+        return error_response(&config, StatusCode::BAD_REQUEST, RPCError::parse_error());
+    }
+    if decoded.is_none() && config.reject_duplicate_keys {
+        if let Some(key) = json::duplicate_key(&req_body) {
+            log::warn!("Rejecting request repeating member {}", key);
+            let e = RPCError::invalid_request().with_message(&format!("Duplicate member {key}"));
+            return error_response(&config, StatusCode::BAD_REQUEST, e);
+        }
+    }
+
+    // Branching on the first byte rejects what isn't JSON before parsing any of it.
+    let shape = match &decoded {
+        Some(v) => json::value_shape(v),
+        None => json::shape(&req_body),
+    };
+    let Some(mut shape) = shape else {
+        return error_response(&config, StatusCode::BAD_REQUEST, RPCError::parse_error());
+    };
+
+    let direct = match (shape, &decoded) {
+        (Shape::Single, None) => parse_direct::<H>(&req_body, &config),
+        _ => None,
+    };
+    let (body, warnings, cache) = match direct {
+        Some(req) => {
+            let (warnings, cache) = match &req {
+                Ok(req) => (
+                    config.deprecation(&req.method).into_iter().collect(),
+                    config.cache_control.get(&req.method).cloned(),
+                ),
+                Err(_) => (vec![], None),
+            };
+            let r = async {
+                match req {
+                    Ok(req) => respond(req, handle.as_ref(), &config).await,
+                    Err(resp) => Some(unhandled(resp)),
+                }
+            };
+            let (resp, redirect) = redirect::capture(r).await;
+            if let Some(r) = redirect {
+                return redirect_response(r);
+            }
+            let Some(resp) = resp else {
+                return no_content();
+            };
+            let direct = response_codec
+                .as_ref()
+                .filter(|_| projection.is_none() && encodes_directly(&config));
+            if let Some(codec) = direct {
+                // Errors are never cached, they may well be gone on the next call.
+                let cache = cache.filter(|_| !resp.is_error());
+                let body = encode_as(resp, &config, codec.as_ref())?;
+                return Ok(response_headers(warnings, cache)
+                    .header("Content-Type", codec.content_type())
+                    .body(full_body(body))?);
+            }
+            (Some(encode(resp, &config)?), warnings, cache)
+        }
+        None => {
+            // Answering instead of failing the service keeps the connection, and any
+            // requests pipelined behind this one, alive.
+            let mut req_body = match decoded {
+                Some(v) => v,
+                None => match serde_json::from_slice::<Value>(&req_body) {
+                    Ok(v) => v,
+                    Err(_) => {
+                        return error_response(
+                            &config,
+                            StatusCode::BAD_REQUEST,
+                            RPCError::parse_error(),
+                        )
+                    }
+                },
+            };
+            config.log_body("Request", &req_body);
+            if let Some(transform) = &config.request_transform {
+                req_body = transform(req_body);
+                let Some(s) = json::value_shape(&req_body) else {
+                    return error_response(
+                        &config,
+                        StatusCode::BAD_REQUEST,
+                        RPCError::invalid_request(),
+                    );
+                };
+                shape = s;
+            }
+
+            let warnings = config.deprecations(&req_body);
+            let cache = match shape {
+                Shape::Single => req_body
+                    .get("method")
+                    .and_then(Value::as_str)
+                    .and_then(|m| config.cache_control.get(m))
+                    .cloned(),
+                Shape::Batch => None,
+            };
+
+            if matches!(shape, Shape::Batch) {
+                if let Err(e) = validate_request(&req_body) {
+                    return error_response(&config, StatusCode::BAD_REQUEST, e);
+                }
+            }
+            let body = match shape {
+                Shape::Single => {
+                    let (body, redirect) =
+                        redirect::capture(_handle(req_body, handle.as_ref(), &config)).await;
+                    if let Some(r) = redirect {
+                        return redirect_response(r);
+                    }
+                    body?
+                }
+                Shape::Batch if config.stream_batches => {
+                    return stream_batch(req_body, handle, config, gzip);
+                }
+                Shape::Batch => _batch_handle(req_body, handle.as_ref(), &config).await?,
+            };
+            (body, warnings, cache)
+        }
+    };
+    let Some(body) = body else {
+        return no_content();
+    };
+    let body = match projection {
+        Some(p) => project(&body, &p, &config)?,
+        None => body,
+    };
+    // Errors are never cached, they may well be gone on the next call.
+    let cache = cache.filter(|_| json::is_result(&body));
+    let body = match &config.response_transform {
+        Some(transform) => transform(serde_json::from_str(&body)?).to_string(),
+        None => body,
+    };
+    if config.log_bodies {
+        config.log_body("Response", &serde_json::from_str(&body)?);
+    }
 
-        if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
-            println!("Error serving connection: {:?}", err);
+    let builder = response_headers(warnings, cache);
+
+    if let Some(codec) = response_codec {
+        let v: Value = serde_json::from_str(&body)?;
+        return Ok(builder
+            .header("Content-Type", codec.content_type())
+            .body(full_body(codec.encode_response(&v)?))?);
+    }
+
+    let resp = builder
+        .header("Content-Type", &config.content_type)
+        .body(full_body(body))?;
+
+    Ok(resp)
+}
+
+/// A response builder with the `Warning` and `Cache-Control` headers of a call.
+fn response_headers(
+    warnings: Vec<String>,
+    cache: Option<String>,
+) -> hyper::http::response::Builder {
+    let mut builder = Response::builder();
+    for w in warnings {
+        let value = format!("299 - \"{}\"", w.replace('\\', "\\\\").replace('"', "\\\""));
+        match HeaderValue::from_str(&value) {
+            Ok(v) => builder = builder.header(header::WARNING, v),
+            Err(_) => log::debug!("Warning not sendable as a header: {}", value),
+        }
+    }
+    if let Some(directive) = cache {
+        match HeaderValue::from_str(&directive) {
+            Ok(v) => builder = builder.header(header::CACHE_CONTROL, v),
+            Err(_) => log::warn!("Invalid Cache-Control directive: {}", directive),
         }
     }
+    builder
+}
+
+/// The header a client lists [`ServerConfig::result_fields`] in.
+const RESULT_FIELDS: &str = "x-result-fields";
+
+/// Re-encodes the response or batch of responses in `body` with trimmed results.
+fn project(body: &str, projection: &Projection, config: &ServerConfig) -> Result<String> {
+    let trim = |mut resp: RPCResponse<Value>| {
+        if let ResponsePayload::Result(Some(v)) = resp.payload {
+            resp.payload = ResponsePayload::Result(Some(projection.apply(v)));
+        }
+        encode(resp, config)
+    };
+    match json::shape(body.as_bytes()) {
+        Some(Shape::Batch) => {
+            let responses: Vec<RPCResponse<Value>> = serde_json::from_str(body)?;
+            let r = responses
+                .into_iter()
+                .map(trim)
+                .collect::<Result<Vec<_>>>()?;
+            Ok(format!("[{}]", r.join(",")))
+        }
+        _ => trim(serde_json::from_str(body)?),
+    }
+}
+
+impl<H> Service<Request<Incoming>> for HandleHttp<H>
+where
+    H: Handle + Send + Sync + 'static,
+    H::Request: Debug,
+{
+    type Response = Response<ResponseBody>;
+    type Error = anyhow::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn call(&self, request: Request<Incoming>) -> Self::Future {
+        let handle = self.handle.clone();
+        let config = self.config.clone();
+        let state = self.state.clone();
+        let peer = self.peer;
+
+        let r = async move {
+            if config
+                .health_path
+                .as_ref()
+                .is_some_and(|p| p == request.uri().path())
+            {
+                let (status, body) = match state.draining.load(Ordering::SeqCst) {
+                    true => (StatusCode::SERVICE_UNAVAILABLE, r#"{"status":"draining"}"#),
+                    false => (StatusCode::OK, r#"{"status":"ok"}"#),
+                };
+                return Ok(Response::builder()
+                    .status(status)
+                    .header("Content-Type", "application/json")
+                    .body(full_body(body))?);
+            }
+            if config.playground
+                && request.method() == Method::GET
+                && request.uri().path() == "/"
+                && !ws::is_upgrade(&request)
+            {
+                return Ok(Response::builder()
+                    .header("Content-Type", "text/html; charset=utf-8")
+                    .body(full_body(playground(config.rpc_path.as_deref())))?);
+            }
+            if config
+                .rpc_path
+                .as_ref()
+                .is_some_and(|p| p != request.uri().path())
+            {
+                return match &config.not_found_handler {
+                    Some(not_found) => Ok(not_found(&request)),
+                    None => Ok(Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(full_body(Bytes::new()))?),
+                };
+            }
+            if request.method() != Method::POST && !ws::is_upgrade(&request) {
+                return Ok(Response::builder()
+                    .status(StatusCode::METHOD_NOT_ALLOWED)
+                    .header(header::ALLOW, "POST")
+                    .body(full_body(Bytes::new()))?);
+            }
+
+            let guard = InFlightGuard::new(state.clone());
+            let current = guard.current;
+            let resp = match state.refusal(&config, current) {
+                Some(e) => error_response(&config, StatusCode::SERVICE_UNAVAILABLE, e),
+                None => handle_request(request, handle, config, peer, state.clone()).await,
+            };
+            guard.finish();
+            resp
+        };
+
+        Box::pin(r)
+    }
+}
+
+/// Answers one HTTP request as a [`Server`] does once it has routed it to JSON-RPC,
+/// for mounting in another hyper-based server, e.g. as an axum route:
+///
+/// ```ignore
+/// let rpc = move |ConnectInfo(peer): ConnectInfo<SocketAddr>, req: axum::extract::Request| {
+///     let (handle, config) = (handle.clone(), config.clone());
+///     async move {
+///         match handle_hyper_request(req, handle, config, peer).await {
+///             Ok(resp) => resp.map(axum::body::Body::new),
+///             Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+///         }
+///     }
+/// };
+/// let app = Router::new().route("/rpc", post(rpc));
+/// ```
+///
+/// `examples/embed` runs a whole axum app this way.
+///
+/// Authentication, middleware and WebSocket upgrades apply as usual. Routing is up to
+/// the caller, so `rpc_path`, `health_path` and the playground don't, nor do the
+/// server-wide `max_in_flight` and maintenance mode; `max_in_flight` still limits the
+/// calls running at once on each WebSocket connection. Fails only when no response can
+/// be built at all, in which case a `Server` drops the connection.
+pub async fn handle_hyper_request<H, B>(
+    request: Request<B>,
+    handle: Arc<H>,
+    config: Arc<ServerConfig>,
+    peer: SocketAddr,
+) -> Result<Response<ResponseBody>>
+where
+    H: Handle + Send + Sync + 'static,
+    H::Request: Debug,
+    B: Body<Data = Bytes>,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    // Each connection counts its calls on its own, outside of any server.
+    handle_request(request, handle, config, peer, Arc::default()).await
+}
+
+/// Like [`handle_hyper_request`], with WebSocket calls counted as in flight in `state`.
+async fn handle_request<H, B>(
+    request: Request<B>,
+    handle: Arc<H>,
+    config: Arc<ServerConfig>,
+    peer: SocketAddr,
+    state: Arc<ServerState>,
+) -> Result<Response<ResponseBody>>
+where
+    H: Handle + Send + Sync + 'static,
+    H::Request: Debug,
+    B: Body<Data = Bytes>,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    if !config.authorized(request.headers()) {
+        return error_response(&config, StatusCode::UNAUTHORIZED, RPCError::unauthorized());
+    }
+    let mut request_ctx = RequestContext::new(peer, request.headers().clone());
+    if let Err(e) = config
+        .middleware
+        .iter()
+        .try_for_each(|m| m(&mut request_ctx))
+    {
+        return error_response(&config, e.http_status(), e);
+    }
+    if config.websocket && ws::is_upgrade(&request) {
+        return ws::upgrade(request, handle, config, request_ctx, state);
+    }
+    let remote = TraceContext::from_headers(request.headers());
+    let ctx = remote
+        .as_ref()
+        .map_or_else(TraceContext::new_root, TraceContext::child);
+    let span = tracing::info_span!(
+        "rpc",
+        trace_id = %ctx.trace_id,
+        span_id = %ctx.span_id,
+        parent_span_id = remote.as_ref().map(|c| c.span_id.as_str()),
+    );
+    let r = process(request, handle, config.clone()).instrument(span);
+    request_ctx.scope(ctx.scope(r)).await
+}
+
+/// The playground page, sending its calls to `rpc_path` or `/`.
+fn playground(rpc_path: Option<&str>) -> String {
+    let endpoint = serde_json::to_string(rpc_path.unwrap_or("/")).unwrap_or_default();
+    include_str!("playground.html").replace("__ENDPOINT__", &endpoint)
+}
+
+pub async fn serve<H>(addr: &SocketAddr, handle: H) -> Result<()>
+where
+    H: Handle + Send + Sync + 'static,
+    H::Request: Debug,
+{
+    Server::new(handle).serve(addr).await
+}
+
+pub async fn serve_with_config<H>(addr: &SocketAddr, handle: H, config: ServerConfig) -> Result<()>
+where
+    H: Handle + Send + Sync + 'static,
+    H::Request: Debug,
+{
+    Server::with_config(handle, config).serve(addr).await
+}
+
+/// Serves `handle` on all of `addrs` at once, e.g. `0.0.0.0:8080` and `[::]:8080`.
+pub async fn serve_all<H>(addrs: &[SocketAddr], handle: H) -> Result<()>
+where
+    H: Handle + Send + Sync + 'static,
+    H::Request: Debug,
+{
+    Server::new(handle).bind_all(addrs).await?.serve().await
+}
+
+/// A server that can still be controlled at runtime through [`Server::control`].
+pub struct Server<H> {
+    handle: Arc<H>,
+    config: Arc<ServerConfig>,
+    state: Arc<ServerState>,
+}
+
+/// A cloneable handle to toggle a running [`Server`].
+#[derive(Debug, Clone)]
+pub struct ServerControl {
+    state: Arc<ServerState>,
+}
+
+impl ServerControl {
+    /// While on, every call except [`ServerConfig::health_path`] gets 503 `server_busy`.
+    pub fn set_maintenance(&self, on: bool) {
+        self.state.maintenance.store(on, Ordering::SeqCst);
+    }
+
+    pub fn is_maintenance(&self) -> bool {
+        self.state.maintenance.load(Ordering::SeqCst)
+    }
+
+    /// While on, [`ServerConfig::health_path`] answers 503 and every other call gets 503
+    /// `server_busy`, while calls already running finish. Turned on by the shutdown
+    /// signal, see [`ServerConfig::drain_period`].
+    pub fn set_draining(&self, on: bool) {
+        self.state.draining.store(on, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.state.draining.load(Ordering::SeqCst)
+    }
+}
+
+/// A cloneable, read-only view of a running [`Server`]'s load.
+#[derive(Debug, Clone)]
+pub struct ServerStats {
+    state: Arc<ServerState>,
+    latencies: Option<CallLatencies>,
+}
+
+impl ServerStats {
+    /// Open HTTP connections. WebSocket connections stop counting once upgraded.
+    pub fn active_connections(&self) -> usize {
+        self.state.connections.load(Ordering::SeqCst)
+    }
+
+    /// HTTP requests currently being answered.
+    pub fn in_flight(&self) -> usize {
+        self.state.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// See [`CallLatencies::latency_percentile`]. `None` unless
+    /// [`ServerConfig::latencies`] is set.
+    pub fn latency_percentile(&self, method: &str, p: f64) -> Option<Duration> {
+        self.latencies.as_ref()?.latency_percentile(method, p)
+    }
+}
+
+impl<H> Server<H>
+where
+    H: Handle + Send + Sync + 'static,
+    H::Request: Debug,
+{
+    pub fn new(handle: H) -> Self {
+        Self::with_config(handle, ServerConfig::default())
+    }
+
+    pub fn with_config(handle: H, config: ServerConfig) -> Self {
+        Self {
+            handle: Arc::new(handle),
+            config: Arc::new(config),
+            state: Arc::new(ServerState::default()),
+        }
+    }
+
+    pub fn control(&self) -> ServerControl {
+        ServerControl {
+            state: self.state.clone(),
+        }
+    }
+
+    pub fn set_maintenance(&self, on: bool) {
+        self.control().set_maintenance(on)
+    }
+
+    pub fn stats(&self) -> ServerStats {
+        ServerStats {
+            state: self.state.clone(),
+            latencies: self.config.latencies.clone(),
+        }
+    }
+
+    pub async fn serve(self, addr: &SocketAddr) -> Result<()> {
+        self.serve_with_shutdown(addr, std::future::pending()).await
+    }
+
+    /// Runs [`Server::serve`] on its own task, to `select!` on or abort alongside other work.
+    pub fn spawn(self, addr: SocketAddr) -> JoinHandle<Result<()>> {
+        tokio::spawn(async move { self.serve(&addr).await })
+    }
+
+    /// Serves until `signal` resolves, see [`BoundServer::serve_with_shutdown`].
+    pub async fn serve_with_shutdown(
+        self,
+        addr: &SocketAddr,
+        signal: impl Future<Output = ()>,
+    ) -> Result<()> {
+        self.bind(addr).await?.serve_with_shutdown(signal).await
+    }
+
+    /// Binds `addr`, after which connections are queued until the server is served.
+    /// Port 0 picks a free port, see [`BoundServer::local_addr`].
+    pub async fn bind(self, addr: &SocketAddr) -> Result<BoundServer<H>> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(BoundServer {
+            server: self,
+            listeners: vec![listener],
+        })
+    }
+
+    /// Like [`Server::bind`], accepting connections on every one of `addrs`.
+    ///
+    /// IPv6 addresses only take IPv6 connections, so `[::]` and `0.0.0.0` can share a port.
+    pub async fn bind_all(self, addrs: &[SocketAddr]) -> Result<BoundServer<H>> {
+        if addrs.is_empty() {
+            anyhow::bail!("No address to bind");
+        }
+        let listeners = addrs.iter().map(bind_only).collect::<io::Result<_>>()?;
+        Ok(BoundServer {
+            server: self,
+            listeners,
+        })
+    }
+}
+
+/// A listener on `addr` that, for IPv6, leaves IPv4 to a listener of its own.
+fn bind_only(addr: &SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&(*addr).into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// A [`Server`] listening on its sockets, returned by [`Server::bind`].
+pub struct BoundServer<H> {
+    server: Server<H>,
+    listeners: Vec<TcpListener>,
+}
+
+impl<H> BoundServer<H>
+where
+    H: Handle + Send + Sync + 'static,
+    H::Request: Debug,
+{
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listeners[0].local_addr()?)
+    }
+
+    /// The address of each listener, in the order they were bound.
+    pub fn local_addrs(&self) -> Result<Vec<SocketAddr>> {
+        Ok(self
+            .listeners
+            .iter()
+            .map(TcpListener::local_addr)
+            .collect::<io::Result<_>>()?)
+    }
+
+    pub fn control(&self) -> ServerControl {
+        self.server.control()
+    }
+
+    pub fn stats(&self) -> ServerStats {
+        self.server.stats()
+    }
+
+    pub async fn serve(self) -> Result<()> {
+        self.serve_with_shutdown(std::future::pending()).await
+    }
+
+    pub fn spawn(self) -> JoinHandle<Result<()>> {
+        tokio::spawn(self.serve())
+    }
+
+    /// Serves until `signal` resolves, then drains: new calls get 503 `server_busy`,
+    /// for [`ServerConfig::drain_period`] if set while connections are still accepted.
+    /// Then stops accepting connections, lets requests in flight finish and returns
+    /// once every HTTP connection has closed. Upgraded WebSocket connections are not
+    /// waited for.
+    pub async fn serve_with_shutdown(self, signal: impl Future<Output = ()>) -> Result<()> {
+        let Self { server, listeners } = self;
+        for listener in &listeners {
+            println!("Listening on http://{}", listener.local_addr()?);
+        }
+
+        let Server {
+            handle,
+            config,
+            state,
+        } = server;
+
+        let http = config.http1();
+        let (shutdown, _) = watch::channel(());
+        tokio::pin!(signal);
+        let drained = tokio::time::sleep(Duration::ZERO);
+        tokio::pin!(drained);
+        let mut draining = false;
+        loop {
+            let (stream, peer) = tokio::select! {
+                r = future::select_all(listeners.iter().map(|l| Box::pin(l.accept()))) => r.0?,
+                _ = &mut signal, if !draining => {
+                    state.draining.store(true, Ordering::SeqCst);
+                    let Some(period) = config.drain_period else {
+                        break;
+                    };
+                    log::info!("Draining for {:?} before shutting down", period);
+                    drained.as_mut().reset(tokio::time::Instant::now() + period);
+                    draining = true;
+                    continue;
+                }
+                _ = &mut drained, if draining => break,
+            };
+            if !config.allows(peer.ip()) {
+                log::warn!("Rejected connection from {}", peer);
+                continue;
+            }
+            let io = TokioIo::new(stream);
+
+            let service = HandleHttp {
+                handle: handle.clone(),
+                config: config.clone(),
+                state: state.clone(),
+                peer,
+            };
+            let service = service_fn(move |req| service.call(req));
+
+            let http = http.clone();
+            let guard = ConnectionGuard::new(state.clone());
+            let mut stop = shutdown.subscribe();
+            config.spawn(async move {
+                let _guard = guard;
+                let conn = http.serve_connection(io, service).with_upgrades();
+                tokio::pin!(conn);
+                let r = tokio::select! {
+                    r = conn.as_mut() => r,
+                    _ = stop.changed() => {
+                        conn.as_mut().graceful_shutdown();
+                        conn.await
+                    }
+                };
+                match r {
+                    Err(err) if err.is_timeout() => log::debug!("Closed idle connection"),
+                    Err(err) if client_gone(&err) => log::debug!("Client went away: {}", err),
+                    Err(err) => println!("Error serving connection: {:?}", err),
+                    Ok(()) => {}
+                }
+            });
+        }
+
+        drop(listeners);
+        log::info!("Shutting down, waiting for open connections");
+        let _ = shutdown.send(());
+        // Each connection task holds a receiver until it is done.
+        shutdown.closed().await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{
+        http_get, http_post, http_post_ret_string, testing, Auth, Authorizer, BatchLimit,
+        RequestSigner, RetryPolicy, RpcClient,
+    };
+
+    /// Sleeps 20ms per call, counting the most calls running at once.
+    #[derive(Default)]
+    struct Counting {
+        running: AtomicUsize,
+        most: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Handle for Counting {
+        type Request = Value;
+        type Response = Value;
+
+        const BATCH_CONCURRENCY: usize = 2;
+
+        async fn handle(&self, _: &str, req: Option<Value>) -> RPCResult<Option<Value>> {
+            let now = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most.fetch_max(now, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            Ok(req)
+        }
+    }
+
+    /// Fails the first `failures` calls of `flaky` with `temporary_failure` and echoes
+    /// the next ones, leaving other methods to [`testing::TestHandle`].
+    struct Flaky {
+        failures: usize,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Flaky {
+        fn failing(failures: usize) -> Self {
+            Self {
+                failures,
+                calls: Arc::default(),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Handle for Flaky {
+        type Request = Value;
+        type Response = Value;
+
+        async fn handle(&self, method: &str, req: Option<Value>) -> RPCResult<Option<Value>> {
+            match method {
+                "flaky" if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures => {
+                    Err(RPCError::temporary_failure())
+                }
+                "flaky" => Ok(req),
+                _ => testing::TestHandle.handle(method, req).await,
+            }
+        }
+    }
+
+    fn retrying(max_attempts: usize, base_delay: Duration) -> HashMap<String, RetryPolicy> {
+        let policy = RetryPolicy {
+            max_attempts,
+            base_delay,
+            codes: HashSet::from([RPCError::temporary_failure().code]),
+            ..Default::default()
+        };
+        HashMap::from([(String::from("flaky"), policy)])
+    }
+
+    async fn post(addr: SocketAddr, body: String) -> (StatusCode, String) {
+        http_post_ret_string(&format!("http://{addr}"), body.as_bytes(), None)
+            .await
+            .unwrap()
+    }
+
+    fn request(id: u64, method: &str, params: Value) -> String {
+        json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params}).to_string()
+    }
+
+    fn batch(n: usize, method: &str) -> String {
+        let entries = (1..=n)
+            .map(|id| json!({"jsonrpc": "2.0", "id": id, "method": method, "params": [id]}))
+            .collect();
+        Value::Array(entries).to_string()
+    }
+
+    #[tokio::test]
+    async fn streamed_batches_keep_to_batch_concurrency() {
+        let handle = Counting::default();
+        let most = handle.most.clone();
+        let config = ServerConfig {
+            stream_batches: true,
+            ..Default::default()
+        };
+        let addr = testing::serve_handle(handle, config).await;
+
+        let (status, body) = post(addr, batch(6, "m")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.lines().count(), 6);
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn batch_entries_are_retried_timed_out_and_measured() {
+        let latencies = CallLatencies::new();
+        let config = ServerConfig {
+            handler_retries: retrying(2, Duration::from_millis(1)),
+            request_timeout: Some(Duration::from_millis(50)),
+            latencies: Some(latencies.clone()),
+            ..Default::default()
+        };
+        let addr = testing::serve_handle(Flaky::failing(1), config).await;
+
+        let body = r#"[
+            {"jsonrpc": "2.0", "id": 1, "method": "flaky", "params": [1]},
+            {"jsonrpc": "2.0", "id": 2, "method": "sleep", "params": [1000]},
+            {"jsonrpc": "2.0", "id": 3, "method": "echo", "params": [3]}
+        ]"#;
+        let (_, body) = post(addr, body.into()).await;
+        let r: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(r[0]["result"], json!([1]));
+        assert_eq!(r[1]["error"]["code"], RPCError::request_timeout().code);
+        assert_eq!(r[2]["result"], json!([3]));
+        assert_eq!(latencies.count("flaky"), 1);
+        assert_eq!(latencies.count("echo"), 1);
+    }
+
+    /// Sends `request` on a fresh connection, returning the whole raw response.
+    async fn raw(addr: SocketAddr, request: &str) -> String {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.unwrap();
+        resp
+    }
+
+    /// Posts `body` on a fresh connection, returning the whole raw response.
+    async fn raw_post(addr: SocketAddr, body: &str) -> String {
+        let request = format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        raw(addr, &request).await
+    }
+
+    #[tokio::test]
+    async fn only_posts_reach_json_rpc() {
+        let get = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        let addr = testing::serve(ServerConfig::default()).await;
+        let resp = raw(addr, get).await;
+        assert!(resp.starts_with("HTTP/1.1 405"), "{resp}");
+        assert!(resp.to_lowercase().contains("allow: post\r\n"), "{resp}");
+
+        let addr = testing::serve(ServerConfig {
+            playground: true,
+            ..Default::default()
+        })
+        .await;
+        assert!(raw(addr, get).await.starts_with("HTTP/1.1 200"));
+    }
+
+    #[tokio::test]
+    async fn signed_requests_are_verified() {
+        let signer = RequestSigner::new("secret");
+        let addr = testing::serve(ServerConfig {
+            signer: Some(signer.clone()),
+            ..Default::default()
+        })
+        .await;
+        let url = format!("http://{addr}");
+
+        let client = RpcClient::builder()
+            .url(&url)
+            .signer(signer.clone())
+            .build()
+            .unwrap();
+        let r: Option<Value> = client.call("echo", &[1]).await.unwrap();
+        assert_eq!(r, Some(json!([1])));
+
+        let body = br#"{"jsonrpc": "2.0", "id": 1, "method": "echo", "params": [1]}"#;
+        let tampered = br#"{"jsonrpc": "2.0", "id": 1, "method": "echo", "params": [2]}"#;
+        let headers = [("X-Signature", signer.sign(body))];
+        let (status, _) = http_post(&url, tampered, Some(&headers)).await.unwrap();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = http_post(&url, body, Some(&headers)).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn sheds_requests_over_max_in_flight() {
+        let addr = testing::serve(ServerConfig {
+            max_in_flight: Some(1),
+            ..Default::default()
+        })
+        .await;
+        let slow = tokio::spawn(post(addr, request(1, "sleep", json!([300]))));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let (status, body) = post(addr, request(2, "echo", json!([2]))).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.contains("-32000"), "{body}");
+        assert_eq!(slow.await.unwrap().0, StatusCode::OK);
+        assert_eq!(
+            post(addr, request(3, "echo", json!([3]))).await.0,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_nested_too_deeply() {
+        let addr = testing::serve(ServerConfig {
+            max_depth: Some(8),
+            ..Default::default()
+        })
+        .await;
+        let params = format!("{}{}", "[".repeat(10_000), "]".repeat(10_000));
+        let body =
+            format!(r#"{{"jsonrpc": "2.0", "id": 1, "method": "echo", "params": {params}}}"#);
+        let (status, body) = post(addr, body).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("-32700"), "{body}");
+
+        let (status, _) = post(addr, request(2, "echo", json!([[[1]]]))).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn answers_expect_100_continue() {
+        let addr = testing::serve(ServerConfig {
+            max_body_size: Some(1024),
+            ..Default::default()
+        })
+        .await;
+        let body = request(1, "echo", json!([1]));
+        let head = |len: usize| {
+            format!(
+                "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: {len}\r\n\
+                 Expect: 100-continue\r\nConnection: close\r\n\r\n"
+            )
+        };
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(head(body.len()).as_bytes()).await.unwrap();
+        let mut buf = [0; 64];
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(buf[..n].starts_with(b"HTTP/1.1 100 Continue"));
+        stream.write_all(body.as_bytes()).await.unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200"), "{resp}");
+
+        // Too large to accept, so rejected before the body is sent at all.
+        let resp = raw(addr, &head(4096)).await;
+        assert!(resp.starts_with("HTTP/1.1 417"), "{resp}");
+    }
+
+    #[tokio::test]
+    async fn maintenance_mode_answers_503_but_health_checks() {
+        let server = Server::with_config(
+            testing::TestHandle,
+            ServerConfig {
+                health_path: Some(String::from("/health")),
+                ..Default::default()
+            },
+        );
+        let control = server.control();
+        let addr = testing::spawn(server).await;
+        let health = format!("http://{addr}/health");
+
+        control.set_maintenance(true);
+        let (status, body) = post(addr, request(1, "echo", json!([1]))).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.contains("-32000"), "{body}");
+        assert_eq!(
+            http_get(&health, b"", None).await.unwrap().0,
+            StatusCode::OK
+        );
+
+        control.set_maintenance(false);
+        assert_eq!(
+            post(addr, request(2, "echo", json!([2]))).await.0,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn batches_keep_to_batch_concurrency() {
+        let handle = Counting::default();
+        let most = handle.most.clone();
+        let addr = testing::serve_handle(handle, ServerConfig::default()).await;
+        let (_, body) = post(addr, batch(6, "m")).await;
+        let r: Vec<Value> = serde_json::from_str(&body).unwrap();
+        let ids: Vec<_> = r.iter().map(|r| r["id"].as_u64().unwrap()).collect();
+        assert_eq!(ids, [1, 2, 3, 4, 5, 6]);
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+
+        let handle = Counting::default();
+        let requests = (1..=6)
+            .map(|id| RPCRequest {
+                id: Some(RpcId::from(id)),
+                ..RPCRequest::new("m", None)
+            })
+            .collect();
+        assert_eq!(handle.batch_handle(requests).await.len(), 6);
+        assert_eq!(handle.most.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn closes_idle_connections() {
+        let addr = testing::serve(ServerConfig {
+            keepalive_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        })
+        .await;
+        let body = request(1, "echo", json!([1]));
+        let post = format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(post.as_bytes()).await.unwrap();
+        let mut buf = [0; 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(buf[..n].starts_with(b"HTTP/1.1 200"));
+        // Kept alive for the next request, until it has idled too long.
+        let idle = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf));
+        assert_eq!(idle.await.unwrap().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn in_flight_count_recovers_from_errors() {
+        let server = Server::new(testing::TestHandle);
+        let stats = server.stats();
+        let addr = testing::spawn(server).await;
+        let wait_for = |n| {
+            let stats = stats.clone();
+            tokio::time::timeout(Duration::from_secs(5), async move {
+                while stats.in_flight() != n {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            })
+        };
+
+        // The client hangs up while its call is running.
+        let body = request(1, "sleep", json!([60_000]));
+        let post = format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(post.as_bytes()).await.unwrap();
+        wait_for(1).await.unwrap();
+        drop(stream);
+        wait_for(0).await.unwrap();
+
+        // A task holding a guard panics.
+        let state = Arc::new(ServerState::default());
+        let guard = InFlightGuard::new(state.clone());
+        let task = tokio::spawn(async move {
+            let _guard = guard;
+            panic!("handler bug");
+        });
+        assert!(task.await.is_err());
+        assert_eq!(state.in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn counts_active_connections() {
+        let server = Server::new(testing::TestHandle);
+        let stats = server.stats();
+        let addr = testing::spawn(server).await;
+        let wait_for = |n| {
+            let stats = stats.clone();
+            tokio::time::timeout(Duration::from_secs(5), async move {
+                while stats.active_connections() != n {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            })
+        };
+
+        let mut streams = vec![];
+        for _ in 0..3 {
+            streams.push(tokio::net::TcpStream::connect(addr).await.unwrap());
+        }
+        wait_for(3).await.unwrap();
+        streams.pop();
+        wait_for(2).await.unwrap();
+        drop(streams);
+        wait_for(0).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_batches_over_max_concurrent_batches() {
+        let addr = testing::serve(ServerConfig {
+            max_concurrent_batches: Some(BatchLimit::new(1)),
+            ..Default::default()
+        })
+        .await;
+        let slow = format!("[{}]", request(1, "sleep", json!([300])));
+        let slow = tokio::spawn(post(addr, slow));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let (_, body) = post(addr, batch(2, "echo")).await;
+        let r: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(r["error"]["code"], RPCError::server_busy().code);
+        // Single calls don't take a batch slot.
+        assert_eq!(
+            post(addr, request(2, "echo", json!([2]))).await.0,
+            StatusCode::OK
+        );
+
+        slow.await.unwrap();
+        let (_, body) = post(addr, batch(2, "echo")).await;
+        assert_eq!(serde_json::from_str::<Vec<Value>>(&body).unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn strict_members_rejects_extra_members() {
+        let body = r#"{"jsonrpc": "2.0", "id": 1, "method": "echo", "params": [1], "foo": 1}"#;
+        let addr = testing::serve(ServerConfig::default()).await;
+        let (_, resp) = post(addr, body.into()).await;
+        assert_eq!(
+            serde_json::from_str::<Value>(&resp).unwrap()["result"],
+            json!([1])
+        );
+
+        let addr = testing::serve(ServerConfig {
+            strict_members: true,
+            ..Default::default()
+        })
+        .await;
+        let (_, resp) = post(addr, body.into()).await;
+        let r: Value = serde_json::from_str(&resp).unwrap();
+        assert_eq!(r["error"]["code"], -32600);
+        assert_eq!(r["id"], 1);
+        let (_, resp) = post(addr, request(2, "echo", json!([2]))).await;
+        assert_eq!(
+            serde_json::from_str::<Value>(&resp).unwrap()["result"],
+            json!([2])
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_pipelined_requests_in_order() {
+        let post = |body: String, close: bool| {
+            let connection = if close { "Connection: close\r\n" } else { "" };
+            format!(
+                "POST / HTTP/1.1\r\nHost: localhost\r\n{connection}Content-Length: {}\r\n\r\n{body}",
+                body.len()
+            )
+        };
+        // The slower call comes first, so answering out of order would show.
+        let pipelined = post(request(1, "sleep", json!([100])), false)
+            + &post(request(2, "echo", json!([2])), true);
+
+        for pipeline_flush in [false, true] {
+            let addr = testing::serve(ServerConfig {
+                pipeline_flush,
+                ..Default::default()
+            })
+            .await;
+            let resp = raw(addr, &pipelined).await;
+            assert_eq!(resp.matches("HTTP/1.1 200").count(), 2, "{resp}");
+            let first = resp.find(r#""id":1"#).unwrap();
+            let second = resp.find(r#""id":2"#).unwrap();
+            assert!(first < second, "{resp}");
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_bodies_are_answered_32700() {
+        let addr = testing::serve(ServerConfig::default()).await;
+        let body = request(1, "echo", json!([1]));
+        let head = format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n",
+            body.len() + 10
+        );
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(head.as_bytes()).await.unwrap();
+        stream.write_all(body.as_bytes()).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 400"), "{resp}");
+        assert!(resp.contains("-32700"), "{resp}");
+    }
+
+    #[tokio::test]
+    async fn replaces_responses_over_max_response_bytes() {
+        let addr = testing::serve(ServerConfig {
+            max_response_bytes: Some(100),
+            ..Default::default()
+        })
+        .await;
+        let (_, body) = post(addr, request(1, "echo", json!(["x".repeat(200)]))).await;
+        let r: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(r["error"]["code"], RPCError::response_too_large().code);
+        assert_eq!(r["id"], 1);
+
+        let (_, body) = post(addr, request(2, "echo", json!(["x"]))).await;
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap()["result"],
+            json!(["x"])
+        );
+    }
+
+    #[tokio::test]
+    async fn replaces_responses_nested_past_max_response_depth() {
+        let addr = testing::serve(ServerConfig {
+            max_response_depth: Some(4),
+            ..Default::default()
+        })
+        .await;
+        let (_, body) = post(addr, request(1, "echo", json!([[[[[1]]]]]))).await;
+        let r: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(r["error"]["code"], -32603);
+        assert_eq!(r["id"], 1);
+
+        let (_, body) = post(addr, request(2, "echo", json!([[1]]))).await;
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap()["result"],
+            json!([[1]])
+        );
+    }
+
+    #[tokio::test]
+    async fn empty_results_of_non_empty_methods_are_answered_32603() {
+        let addr = testing::serve(ServerConfig {
+            non_empty_results: HashSet::from([String::from("echo")]),
+            ..Default::default()
+        })
+        .await;
+        let call = json!({"jsonrpc": "2.0", "id": 1, "method": "echo"}).to_string();
+        let (_, body) = post(addr, call).await;
+        let r: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(r["error"]["code"], -32603);
+        assert_eq!(r["id"], 1);
+
+        let (_, body) = post(addr, request(2, "echo", json!([1]))).await;
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap()["result"],
+            json!([1])
+        );
+    }
+
+    #[tokio::test]
+    async fn bind_all_serves_every_address() {
+        let local = SocketAddr::from(([127, 0, 0, 1], 0));
+        let bound = Server::new(testing::TestHandle)
+            .bind_all(&[local, local])
+            .await
+            .unwrap();
+        let addrs = bound.local_addrs().unwrap();
+        bound.spawn();
+
+        assert_eq!(addrs.len(), 2);
+        assert_ne!(addrs[0], addrs[1]);
+        for addr in addrs {
+            let (_, body) = post(addr, request(1, "echo", json!([1]))).await;
+            assert_eq!(
+                serde_json::from_str::<Value>(&body).unwrap()["result"],
+                json!([1])
+            );
+        }
+        assert!(Server::new(testing::TestHandle)
+            .bind_all(&[])
+            .await
+            .is_err());
+    }
+
+    /// Only the `admin` token may call `echo`.
+    struct AdminOnlyEcho;
+
+    #[async_trait]
+    impl Authorizer for AdminOnlyEcho {
+        async fn authorize(&self, method: &str, ctx: &RequestContext) -> RPCResult<()> {
+            let admin = Auth::bearer("admin").header_value();
+            match ctx.headers.get("Authorization") {
+                Some(v) if method == "echo" && *v != admin.as_str() => Err(RPCError::forbidden()),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn authorizer_denies_admin_methods_to_other_tokens() {
+        let addr = testing::serve(ServerConfig {
+            auth: vec![Auth::bearer("admin"), Auth::bearer("user")],
+            authorizer: Some(Arc::new(AdminOnlyEcho)),
+            ..Default::default()
+        })
+        .await;
+        let call = |token: &'static str, method: &'static str| async move {
+            let headers = [("Authorization", Auth::bearer(token).header_value())];
+            let body = request(1, method, json!([1]));
+            let url = format!("http://{addr}");
+            let (_, body) = http_post_ret_string(&url, body.as_bytes(), Some(&headers))
+                .await
+                .unwrap();
+            serde_json::from_str::<Value>(&body).unwrap()
+        };
+
+        let denied = call("user", "echo").await;
+        assert_eq!(denied["error"]["code"], RPCError::forbidden().code);
+        assert_eq!(call("admin", "echo").await["result"], json!([1]));
+        assert_eq!(call("user", "sleep").await["result"], json!([1]));
+    }
+
+    #[tokio::test]
+    async fn reject_duplicate_keys_rejects_a_second_method() {
+        let body =
+            r#"{"jsonrpc": "2.0", "id": 1, "method": "fail", "method": "echo", "params": [1]}"#;
+        let lenient = testing::serve(Default::default()).await;
+        let (_, resp) = post(lenient, String::from(body)).await;
+        assert_eq!(
+            serde_json::from_str::<Value>(&resp).unwrap()["result"],
+            json!([1])
+        );
+
+        let strict = testing::serve(ServerConfig {
+            reject_duplicate_keys: true,
+            ..Default::default()
+        })
+        .await;
+        let (status, resp) = post(strict, String::from(body)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let r: Value = serde_json::from_str(&resp).unwrap();
+        assert_eq!(r["error"]["code"], -32600);
+        assert_eq!(r["error"]["message"], "Duplicate member method");
+    }
+
+    #[tokio::test]
+    async fn no_params_methods_reject_params() {
+        let addr = testing::serve(ServerConfig {
+            no_params: HashSet::from([String::from("echo")]),
+            ..Default::default()
+        })
+        .await;
+        let (_, body) = post(addr, request(1, "echo", json!([1]))).await;
+        let r: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(r["error"]["code"], -32602);
+        assert_eq!(r["error"]["data"], "echo takes no params");
+
+        for params in [json!([]), json!({})] {
+            let (_, body) = post(addr, request(2, "echo", params.clone())).await;
+            assert_eq!(
+                serde_json::from_str::<Value>(&body).unwrap()["result"],
+                params
+            );
+        }
+        let (_, body) = post(addr, request(3, "sleep", json!([1]))).await;
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap()["result"],
+            json!([1])
+        );
+    }
+
+    #[tokio::test]
+    async fn fractional_ids_are_echoed_unless_rejected() {
+        let call = json!({"jsonrpc": "2.0", "id": 1.5, "method": "echo", "params": [1]});
+        let lenient = testing::serve(Default::default()).await;
+        let (_, body) = post(lenient, call.to_string()).await;
+        let r: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!((&r["id"], &r["result"]), (&json!(1.5), &json!([1])));
+
+        let strict = testing::serve(ServerConfig {
+            reject_fractional_ids: true,
+            ..Default::default()
+        })
+        .await;
+        let (_, body) = post(strict, call.to_string()).await;
+        let r: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            (&r["id"], &r["error"]["code"]),
+            (&Value::Null, &json!(-32600))
+        );
+        let (_, body) = post(
+            strict,
+            json!([call, {"jsonrpc": "2.0", "id": 2, "method": "echo"}]).to_string(),
+        )
+        .await;
+        let r: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            (&r[0]["id"], &r[0]["error"]["code"]),
+            (&Value::Null, &json!(-32600))
+        );
+        assert_eq!(r[1]["id"], 2);
+    }
+
+    #[tokio::test]
+    async fn draining_fails_health_checks_and_new_calls() {
+        let server = Server::with_config(
+            testing::TestHandle,
+            ServerConfig {
+                health_path: Some(String::from("/health")),
+                drain_period: Some(Duration::from_secs(5)),
+                ..Default::default()
+            },
+        );
+        let (control, stats) = (server.control(), server.stats());
+        let bound = server
+            .bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let addr = bound.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        tokio::spawn(bound.serve_with_shutdown(async move {
+            stopped.await.ok();
+        }));
+        let health = format!("http://{addr}/health");
+        assert_eq!(
+            http_get(&health, b"", None).await.unwrap().0,
+            StatusCode::OK
+        );
+
+        let running = tokio::spawn(post(addr, request(1, "sleep", json!([300]))));
+        while stats.in_flight() == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        stop.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert!(control.is_draining());
+        let (status, body) = http_get(&health, b"", None).await.unwrap();
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body, br#"{"status":"draining"}"#);
+        let (status, body) = post(addr, request(2, "echo", json!([2]))).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.contains("-32000"), "{body}");
+        let (status, body) = running.await.unwrap();
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap()["result"],
+            json!([300])
+        );
+    }
+
+    #[tokio::test]
+    async fn handlers_are_retried_with_backoff_until_they_succeed() {
+        let handle = Flaky::failing(3);
+        let calls = handle.calls.clone();
+        let addr = testing::serve_handle(
+            handle,
+            ServerConfig {
+                handler_retries: retrying(4, Duration::from_millis(20)),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let started = Instant::now();
+        let (_, body) = post(addr, request(1, "flaky", json!([1]))).await;
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap()["result"],
+            json!([1])
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+        // 20ms, 40ms then 80ms between the attempts.
+        assert!(started.elapsed() >= Duration::from_millis(140));
+
+        let (_, body) = post(addr, request(2, "echo", json!([2]))).await;
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap()["result"],
+            json!([2])
+        );
+    }
+
+    #[tokio::test]
+    async fn handlers_failing_every_attempt_give_the_last_error() {
+        let handle = Flaky::failing(usize::MAX);
+        let calls = handle.calls.clone();
+        let addr = testing::serve_handle(
+            handle,
+            ServerConfig {
+                handler_retries: retrying(3, Duration::from_millis(1)),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let (_, body) = post(addr, request(1, "flaky", json!([1]))).await;
+        let r: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(r["error"]["code"], RPCError::temporary_failure().code);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn answers_msgpack_to_clients_accepting_it() {
+        let direct = testing::serve(ServerConfig {
+            msgpack: true,
+            ..Default::default()
+        })
+        .await;
+        // Logging bodies needs the response as JSON first.
+        let logged = testing::serve(ServerConfig {
+            msgpack: true,
+            log_bodies: true,
+            ..Default::default()
+        })
+        .await;
+        let call = |addr: SocketAddr, body: String| async move {
+            let headers = [("Accept", String::from("application/msgpack"))];
+            let url = format!("http://{addr}");
+            let (status, bytes) = http_post(&url, body.as_bytes(), Some(&headers))
+                .await
+                .unwrap();
+            assert_eq!(status, StatusCode::OK);
+            bytes
+        };
+
+        let ok = call(direct, request(1, "echo", json!({"a": [1, "b"]}))).await;
+        let r: RPCResponse<Value> = rmp_serde::from_slice(&ok).unwrap();
+        assert_eq!(r.id, RpcId::from(1));
+        assert_eq!(r.as_result(), Some(&json!({"a": [1, "b"]})));
+        let failed = call(direct, request(2, "fail", json!([]))).await;
+        let r: RPCResponse<Value> = rmp_serde::from_slice(&failed).unwrap();
+        assert_eq!(
+            r.as_error().unwrap().code,
+            RPCError::temporary_failure().code
+        );
+
+        // Written from the response, the keys keep their order of `jsonrpc`, `result`, `id`.
+        assert_eq!(ok[1..9], *b"\xa7jsonrpc");
+        let decoded = |bytes: &[u8]| rmp_serde::from_slice::<Value>(bytes).unwrap();
+        let again = call(logged, request(1, "echo", json!({"a": [1, "b"]}))).await;
+        assert_eq!(decoded(&again), decoded(&ok));
+        let again = call(logged, request(2, "fail", json!([]))).await;
+        assert_eq!(decoded(&again), decoded(&failed));
+    }
+
+    /// Takes its params as one `u32`, doubling it.
+    struct Double;
+
+    #[async_trait]
+    impl Handle for Double {
+        type Request = (u32,);
+        type Response = u64;
+
+        async fn handle(&self, _: &str, req: Option<(u32,)>) -> RPCResult<Option<u64>> {
+            Ok(req.map(|(n,)| u64::from(n) * 2))
+        }
+    }
+
+    #[tokio::test]
+    async fn integers_out_of_range_are_answered_32602() {
+        // Logging bodies parses params from a `Value` rather than straight from the body.
+        for log_bodies in [false, true] {
+            let config = ServerConfig {
+                log_bodies,
+                ..Default::default()
+            };
+            let addr = testing::serve_handle(Double, config).await;
+            let (_, body) = post(addr, request(1, "double", json!([u32::MAX]))).await;
+            let r: Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(r["result"], u64::from(u32::MAX) * 2);
+
+            let (_, body) = post(addr, request(2, "double", json!([1u64 << 32]))).await;
+            let r: Value = serde_json::from_str(&body).unwrap();
+            assert_eq!((&r["id"], &r["error"]["code"]), (&json!(2), &json!(-32602)));
+            let reason = r["error"]["data"].as_str().unwrap();
+            assert!(reason.contains("4294967296"), "{reason}");
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_paths_get_the_configured_not_found_response() {
+        let rpc_path = Some(String::from("/rpc"));
+        let not_found: crate::NotFoundHandler = Arc::new(|req| {
+            let body = format!(r#"{{"missing":"{}"}}"#, req.uri().path());
+            Response::builder()
+                .status(StatusCode::GONE)
+                .header("Content-Type", "application/json")
+                .body(full_body(body))
+                .unwrap()
+        });
+        let custom = testing::serve(ServerConfig {
+            rpc_path: rpc_path.clone(),
+            not_found_handler: Some(not_found),
+            ..Default::default()
+        })
+        .await;
+        let (status, body) = http_get(&format!("http://{custom}/old"), b"", None)
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::GONE);
+        assert_eq!(body, br#"{"missing":"/old"}"#);
+        let url = format!("http://{custom}/rpc");
+        let body = request(1, "echo", json!([1]));
+        let (_, body) = http_post_ret_string(&url, body.as_bytes(), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap()["result"],
+            json!([1])
+        );
+
+        let default = testing::serve(ServerConfig {
+            rpc_path,
+            ..Default::default()
+        })
+        .await;
+        let (status, body) = http_get(&format!("http://{default}/old"), b"", None)
+            .await
+            .unwrap();
+        assert_eq!((status, body.len()), (StatusCode::NOT_FOUND, 0));
+    }
+
+    #[tokio::test]
+    async fn empty_batches_are_answered_with_one_32600() {
+        let streamed = ServerConfig {
+            stream_batches: true,
+            ..Default::default()
+        };
+        for config in [ServerConfig::default(), streamed] {
+            let addr = testing::serve(config).await;
+            let (status, body) = post(addr, String::from("[]")).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            let r: Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(
+                (&r["id"], &r["error"]["code"]),
+                (&Value::Null, &json!(-32600))
+            );
+            assert_eq!(r["error"]["data"], "empty batch");
+        }
+    }
+
+    #[tokio::test]
+    async fn notifications_get_no_response() {
+        let handle = Flaky::failing(0);
+        let calls = handle.calls.clone();
+        let addr = testing::serve_handle(handle, ServerConfig::default()).await;
+        let notify = |method: &str| json!({"jsonrpc": "2.0", "method": method, "params": [1]});
+
+        let (status, body) = post(addr, notify("flaky").to_string()).await;
+        assert_eq!((status, body.as_str()), (StatusCode::NO_CONTENT, ""));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        // Not even an error is sent back.
+        let (status, body) = post(addr, notify("nope").to_string()).await;
+        assert_eq!((status, body.as_str()), (StatusCode::NO_CONTENT, ""));
+
+        let batch = json!([notify("flaky"), notify("nope")]).to_string();
+        let (status, body) = post(addr, batch).await;
+        assert_eq!((status, body.as_str()), (StatusCode::NO_CONTENT, ""));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let call = json!({"jsonrpc": "2.0", "id": 1, "method": "echo", "params": [1]});
+        let batch = json!([notify("flaky"), call, notify("nope")]).to_string();
+        let (_, body) = post(addr, batch).await;
+        let r: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(r, json!([{"jsonrpc": "2.0", "result": [1], "id": 1}]));
+    }
+
+    #[tokio::test]
+    async fn streamed_batches_leave_notifications_out() {
+        let addr = testing::serve(ServerConfig {
+            stream_batches: true,
+            ..Default::default()
+        })
+        .await;
+        let batch = json!([
+            {"jsonrpc": "2.0", "method": "echo", "params": [1]},
+            {"jsonrpc": "2.0", "id": 2, "method": "echo", "params": [2]},
+        ]);
+        let (_, body) = post(addr, batch.to_string()).await;
+        let lines: Vec<Value> = body
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines, [json!({"jsonrpc": "2.0", "result": [2], "id": 2})]);
+    }
+
+    #[tokio::test]
+    async fn overridden_methods_get_their_canned_answer() {
+        let addr = testing::serve(ServerConfig {
+            method_overrides: HashMap::from([
+                ("echo".to_string(), Err(RPCError::server_busy())),
+                ("trace".to_string(), Ok(json!("stubbed"))),
+            ]),
+            ..Default::default()
+        })
+        .await;
+
+        let (_, body) = post(addr, request(1, "echo", json!([1]))).await;
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"]["code"], RPCError::server_busy().code);
+        assert_eq!(body["id"], 1);
+        let (_, body) = post(addr, request(2, "trace", json!([]))).await;
+        assert_eq!(body, r#"{"jsonrpc":"2.0","result":"stubbed","id":2}"#);
+    }
+
+    #[tokio::test]
+    async fn overridden_methods_are_still_checked_and_authorized() {
+        let addr = testing::serve(ServerConfig {
+            auth: vec![Auth::bearer("admin"), Auth::bearer("user")],
+            authorizer: Some(Arc::new(AdminOnlyEcho)),
+            method_prefixes: vec!["ec".to_string()],
+            method_overrides: HashMap::from([
+                ("echo".to_string(), Ok(json!("stubbed"))),
+                ("trace".to_string(), Ok(json!("stubbed"))),
+            ]),
+            ..Default::default()
+        })
+        .await;
+        let call = |token: &'static str, method: &'static str| async move {
+            let headers = [("Authorization", Auth::bearer(token).header_value())];
+            let body = request(1, method, json!([1]));
+            let url = format!("http://{addr}");
+            let (_, body) = http_post_ret_string(&url, body.as_bytes(), Some(&headers))
+                .await
+                .unwrap();
+            serde_json::from_str::<Value>(&body).unwrap()
+        };
+
+        let denied = call("user", "echo").await;
+        assert_eq!(denied["error"]["code"], RPCError::forbidden().code);
+        let unlisted = call("admin", "trace").await;
+        assert_eq!(unlisted["error"]["code"], RPCError::unknown_method().code);
+        let allowed = call("admin", "echo").await;
+        assert_eq!(allowed["result"], "stubbed");
+    }
+
+    /// Takes its params as a name or a list of numbers.
+    struct Either;
+
+    #[derive(Clone, Debug, Deserialize)]
+    #[serde(untagged)]
+    enum NameOrNumbers {
+        Name(String),
+        Numbers(Vec<u32>),
+    }
+
+    #[async_trait]
+    impl Handle for Either {
+        type Request = NameOrNumbers;
+        type Response = Value;
+
+        async fn handle(&self, _: &str, req: Option<NameOrNumbers>) -> RPCResult<Option<Value>> {
+            Ok(req.map(|r| match r {
+                NameOrNumbers::Name(name) => json!(name),
+                NameOrNumbers::Numbers(ns) => json!(ns.iter().sum::<u32>()),
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn params_matching_no_variant_are_answered_32602_with_the_reason() {
+        for log_bodies in [false, true] {
+            let config = ServerConfig {
+                log_bodies,
+                ..Default::default()
+            };
+            let addr = testing::serve_handle(Either, config).await;
+            let (_, body) = post(addr, request(1, "either", json!([1, 2]))).await;
+            let r: Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(r["result"], 3);
+
+            let (_, body) = post(addr, request(2, "either", json!({"name": true}))).await;
+            let r: Value = serde_json::from_str(&body).unwrap();
+            assert_eq!((&r["id"], &r["error"]["code"]), (&json!(2), &json!(-32602)));
+            let reason = r["error"]["data"].as_str().unwrap();
+            assert!(reason.contains("did not match any variant"), "{reason}");
+        }
+    }
+
+    #[tokio::test]
+    async fn deprecated_methods_are_answered_with_a_warning() {
+        // Logging bodies answers from a `Value` rather than straight from the body.
+        for log_bodies in [false, true] {
+            let addr = testing::serve(ServerConfig {
+                log_bodies,
+                deprecated: HashMap::from([("echo".to_string(), Some("trace".to_string()))]),
+                ..Default::default()
+            })
+            .await;
+            let warning = "warning: 299 - \"echo is deprecated, use trace instead\"\r\n";
+
+            let resp = raw_post(addr, &request(1, "echo", json!([1]))).await;
+            assert!(resp.starts_with("HTTP/1.1 200"), "{resp}");
+            assert!(resp.to_lowercase().contains(warning), "{resp}");
+            assert!(
+                resp.ends_with(r#"{"jsonrpc":"2.0","result":[1],"id":1}"#),
+                "{resp}"
+            );
+            let resp = raw_post(addr, &batch(2, "echo")).await;
+            assert!(resp.to_lowercase().contains(warning), "{resp}");
+            let resp = raw_post(addr, &request(1, "trace", json!([]))).await;
+            assert!(!resp.to_lowercase().contains("warning:"), "{resp}");
+        }
+    }
+
+    /// Sleeps for as many milliseconds as its first param, whatever the method.
+    struct Nap;
+
+    #[async_trait]
+    impl Handle for Nap {
+        type Request = Value;
+        type Response = Value;
+
+        async fn handle(&self, method: &str, req: Option<Value>) -> RPCResult<Option<Value>> {
+            testing::TestHandle
+                .handle("sleep", req)
+                .await
+                .map(|_| Some(json!(method)))
+        }
+    }
+
+    #[tokio::test]
+    async fn method_timeouts_replace_the_default_one() {
+        let addr = testing::serve_handle(
+            Nap,
+            ServerConfig {
+                request_timeout: Some(Duration::from_secs(5)),
+                method_timeouts: HashMap::from([("hasty".to_string(), Duration::from_millis(20))]),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let (_, body) = post(addr, request(1, "hasty", json!([200]))).await;
+        let r: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(r["error"]["code"], RPCError::request_timeout().code);
+        let (_, body) = post(addr, request(2, "patient", json!([200]))).await;
+        assert_eq!(body, r#"{"jsonrpc":"2.0","result":"patient","id":2}"#);
+    }
+
+    #[tokio::test]
+    async fn bodies_are_told_apart_by_their_first_byte() {
+        let addr = testing::serve(ServerConfig::default()).await;
+        let (status, body) = post(addr, format!(" \r\n\t{}", batch(2, "echo"))).await;
+        assert_eq!(status, StatusCode::OK);
+        let r: Vec<Value> = serde_json::from_str(&body).unwrap();
+        assert_eq!(r.len(), 2);
+        let (status, body) = post(addr, format!("\n  {}", request(1, "echo", json!([1])))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, r#"{"jsonrpc":"2.0","result":[1],"id":1}"#);
+
+        for odd in ["42", "\"{}\"", "null"] {
+            let (status, body) = post(addr, odd.to_string()).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{odd}");
+            let r: Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(
+                (&r["id"], &r["error"]["code"]),
+                (&Value::Null, &json!(-32700))
+            );
+        }
+    }
+
+    /// The caller's tenant, as middleware found it in the `x-tenant` header.
+    #[derive(Clone)]
+    struct Tenant(String);
+
+    /// Answers every call with the [`Tenant`] of its request.
+    struct TenantEcho;
+
+    #[async_trait]
+    impl Handle for TenantEcho {
+        type Request = Value;
+        type Response = Value;
+
+        async fn handle(&self, _: &str, _: Option<Value>) -> RPCResult<Option<Value>> {
+            Ok(RequestContext::extension::<Tenant>().map(|t| json!(t.0)))
+        }
+    }
+
+    #[tokio::test]
+    async fn handlers_read_what_middleware_adds() {
+        let tenant: crate::Middleware = Arc::new(|ctx| {
+            let tenant = ctx
+                .headers
+                .get("x-tenant")
+                .ok_or_else(RPCError::forbidden)?;
+            let tenant = tenant.to_str().map_err(|_| RPCError::forbidden())?;
+            ctx.extensions.insert(Tenant(tenant.to_string()));
+            Ok(())
+        });
+        let config = ServerConfig {
+            middleware: vec![tenant],
+            ..Default::default()
+        };
+        let addr = testing::serve_handle(TenantEcho, config).await;
+        let url = format!("http://{addr}");
+        let body = request(1, "whoami", json!([]));
+
+        let headers = [("x-tenant", String::from("acme"))];
+        let (_, resp) = http_post_ret_string(&url, body.as_bytes(), Some(&headers))
+            .await
+            .unwrap();
+        assert_eq!(resp, r#"{"jsonrpc":"2.0","result":"acme","id":1}"#);
+        let (status, resp) = post(addr, body).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let resp: Value = serde_json::from_str(&resp).unwrap();
+        assert_eq!(resp["error"]["code"], RPCError::forbidden().code);
+    }
+
+    #[tokio::test]
+    async fn only_methods_with_an_allowed_prefix_are_dispatched() {
+        let addr = testing::serve(ServerConfig {
+            method_prefixes: vec!["ec".to_string(), "sl".to_string()],
+            ..Default::default()
+        })
+        .await;
+
+        let (_, body) = post(addr, request(1, "echo", json!([1]))).await;
+        assert_eq!(body, r#"{"jsonrpc":"2.0","result":[1],"id":1}"#);
+        // `trace` would answer, were it let through.
+        let (_, body) = post(addr, request(2, "trace", json!([]))).await;
+        let r: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!((&r["id"], &r["error"]["code"]), (&json!(2), &json!(-32601)));
+
+        let (_, body) = post(
+            addr,
+            format!(
+                "[{},{}]",
+                request(3, "sleep", json!([1])),
+                request(4, "trace", json!([]))
+            ),
+        )
+        .await;
+        let r: Vec<Value> = serde_json::from_str(&body).unwrap();
+        assert_eq!(r[0]["result"], json!([1]));
+        assert_eq!(
+            (&r[1]["id"], &r[1]["error"]["code"]),
+            (&json!(4), &json!(-32601))
+        );
+    }
+
+    #[tokio::test]
+    async fn read_methods_are_sent_with_cache_control() {
+        // Logging bodies answers from a `Value` rather than straight from the body.
+        for log_bodies in [false, true] {
+            let addr = testing::serve(ServerConfig {
+                log_bodies,
+                cache_control: HashMap::from([
+                    ("echo".to_string(), "max-age=60".to_string()),
+                    ("fail".to_string(), "max-age=60".to_string()),
+                ]),
+                ..Default::default()
+            })
+            .await;
+            let cached = |resp: &str| resp.to_lowercase().contains("cache-control:");
+
+            let resp = raw_post(addr, &request(1, "echo", json!([1]))).await;
+            assert!(
+                resp.to_lowercase()
+                    .contains("cache-control: max-age=60\r\n"),
+                "{resp}"
+            );
+            let resp = raw_post(addr, &request(2, "trace", json!([]))).await;
+            assert!(!cached(&resp), "{resp}");
+            let resp = raw_post(addr, &request(3, "fail", json!([]))).await;
+            assert!(!cached(&resp), "{resp}");
+            let resp = raw_post(addr, &batch(2, "echo")).await;
+            assert!(!cached(&resp), "{resp}");
+        }
+    }
+
+    #[tokio::test]
+    async fn results_are_trimmed_to_the_fields_asked_for() {
+        let addr = testing::serve(ServerConfig {
+            result_fields: true,
+            ..Default::default()
+        })
+        .await;
+        let url = format!("http://{addr}");
+        let repo = json!({"name": "rpc", "stars": 5, "owner": {"id": 7, "login": "me"}});
+        let call = |body: String, fields: Option<&'static str>| {
+            let url = url.clone();
+            async move {
+                let headers = fields.map(|f| [("X-Result-Fields", f.to_string())]);
+                let (_, body) =
+                    http_post_ret_string(&url, body.as_bytes(), headers.as_ref().map(|h| &h[..]))
+                        .await
+                        .unwrap();
+                serde_json::from_str::<Value>(&body).unwrap()
+            }
+        };
+
+        let r = call(request(1, "echo", repo.clone()), Some("name,owner.id")).await;
+        assert_eq!(r["result"], json!({"name": "rpc", "owner": {"id": 7}}));
+        let r = call(request(2, "echo", json!([repo, repo])), Some("stars")).await;
+        assert_eq!(r["result"], json!([{"stars": 5}, {"stars": 5}]));
+        let r = call(
+            format!("[{}]", request(3, "echo", repo.clone())),
+            Some("name"),
+        )
+        .await;
+        assert_eq!(r[0]["result"], json!({"name": "rpc"}));
+        let r = call(request(4, "echo", repo.clone()), None).await;
+        assert_eq!(r["result"], repo);
+    }
+
+    #[tokio::test]
+    async fn overrides_are_logged_at_the_call_log_level() {
+        testing::logs();
+        let addr = testing::serve(ServerConfig {
+            call_log_level: log::Level::Trace,
+            method_overrides: HashMap::from([("stubbed_in_logs".to_string(), Ok(json!(1)))]),
+            ..Default::default()
+        })
+        .await;
+        let (_, body) = post(addr, request(1, "stubbed_in_logs", json!([]))).await;
+        assert_eq!(body, r#"{"jsonrpc":"2.0","result":1,"id":1}"#);
+
+        let lines = testing::logged("stubbed_in_logs");
+        assert!(!lines.is_empty());
+        assert!(
+            lines.iter().all(|(level, _)| *level == log::Level::Trace),
+            "{lines:?}"
+        );
+        assert!(lines
+            .iter()
+            .any(|(_, m)| m.contains("answered by its override")));
+    }
 }
@@ -0,0 +1,136 @@
+//! A server for the tests of the other modules to call.
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{server::conn::http1, service::service_fn, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde_json::Value;
+use tokio::net::TcpListener;
+
+use crate::{Handle, RPCError, Server, ServerConfig, TraceContext};
+
+/// `echo` returns its params, `sleep` waits for as many milliseconds as its first
+/// param before returning its params, `trace` returns the id of the trace it runs in,
+/// `fail` answers `temporary_failure` and the rest is unknown.
+pub(crate) struct TestHandle;
+
+#[async_trait]
+impl Handle for TestHandle {
+    type Request = Value;
+    type Response = Value;
+
+    async fn handle(&self, method: &str, req: Option<Value>) -> Result<Option<Value>, RPCError> {
+        match method {
+            "echo" => Ok(req),
+            "sleep" => {
+                let ms = req.as_ref().and_then(|r| r[0].as_u64()).unwrap_or_default();
+                tokio::time::sleep(Duration::from_millis(ms)).await;
+                Ok(req)
+            }
+            "trace" => Ok(TraceContext::current().map(|c| Value::from(c.trace_id))),
+            "fail" => Err(RPCError::temporary_failure()),
+            _ => Err(RPCError::unknown_method()),
+        }
+    }
+}
+
+/// Serves [`TestHandle`] per `config` on a free local port.
+pub(crate) async fn serve(config: ServerConfig) -> SocketAddr {
+    serve_handle(TestHandle, config).await
+}
+
+pub(crate) async fn serve_handle<H>(handle: H, config: ServerConfig) -> SocketAddr
+where
+    H: Handle + Send + Sync + 'static,
+    H::Request: std::fmt::Debug,
+{
+    spawn(Server::with_config(handle, config)).await
+}
+
+/// Serves `server` on a free local port, e.g. after taking its [`Server::control`].
+pub(crate) async fn spawn<H>(server: Server<H>) -> SocketAddr
+where
+    H: Handle + Send + Sync + 'static,
+    H::Request: std::fmt::Debug,
+{
+    let bound = server
+        .bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = bound.local_addr().unwrap();
+    bound.spawn();
+    addr
+}
+
+/// Answers every request with `status` and `body`, counting them, for clients to
+/// meet a server that misbehaves. Returns its url.
+pub(crate) async fn respond_with(
+    status: StatusCode,
+    body: &'static str,
+) -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let hits = Arc::new(AtomicUsize::new(0));
+    let counted = hits.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let hits = counted.clone();
+            let service = service_fn(move |_| {
+                hits.fetch_add(1, Ordering::SeqCst);
+                let resp = Response::builder()
+                    .status(status)
+                    .header("Content-Type", "application/json")
+                    .body(Full::new(Bytes::from_static(body.as_bytes())));
+                async move { resp }
+            });
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+        }
+    });
+    (url, hits)
+}
+
+/// Every log record since the first call, of every test, so each test looks for
+/// its own by something only it logs, e.g. the address of its server.
+pub(crate) fn logs() -> &'static Mutex<Vec<(log::Level, String)>> {
+    static LOGS: OnceLock<Mutex<Vec<(log::Level, String)>>> = OnceLock::new();
+    LOGS.get_or_init(|| {
+        log::set_logger(&Capture).expect("another logger is installed");
+        log::set_max_level(log::LevelFilter::Trace);
+        Mutex::new(vec![])
+    })
+}
+
+/// The records so far logged with `needle` in their message.
+pub(crate) fn logged(needle: &str) -> Vec<(log::Level, String)> {
+    let logs = logs().lock().unwrap();
+    logs.iter()
+        .filter(|(_, m)| m.contains(needle))
+        .cloned()
+        .collect()
+}
+
+struct Capture;
+
+impl log::Log for Capture {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let message = record.args().to_string();
+        logs().lock().unwrap().push((record.level(), message));
+    }
+
+    fn flush(&self) {}
+}
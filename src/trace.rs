@@ -0,0 +1,118 @@
+use std::future::Future;
+
+use hyper::HeaderMap;
+
+tokio::task_local! {
+    static CURRENT: TraceContext;
+}
+
+/// A W3C trace context, read from and written to `traceparent` / `tracestate` headers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    /// 32 lowercase hex digits.
+    pub trace_id: String,
+    /// 16 lowercase hex digits identifying the current span.
+    pub span_id: String,
+    pub flags: u8,
+    pub tracestate: Option<String>,
+}
+
+impl TraceContext {
+    /// Starts a new sampled trace.
+    pub fn new_root() -> Self {
+        Self {
+            trace_id: format!("{:032x}", rand::random::<u128>().max(1)),
+            span_id: new_span_id(),
+            flags: 1,
+            tracestate: None,
+        }
+    }
+
+    /// A new span in the same trace, `self` becoming its parent.
+    pub fn child(&self) -> Self {
+        Self {
+            span_id: new_span_id(),
+            ..self.clone()
+        }
+    }
+
+    pub fn parse(traceparent: &str, tracestate: Option<&str>) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let (version, trace_id, span_id, flags) =
+            (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        let is_hex = |s: &str, len| {
+            s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        };
+        if !is_hex(version, 2) || version == "ff" || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+        if !is_hex(trace_id, 32) || !is_hex(span_id, 16) || !is_hex(flags, 2) {
+            return None;
+        }
+        if trace_id.bytes().all(|b| b == b'0') || span_id.bytes().all(|b| b == b'0') {
+            return None;
+        }
+        Some(Self {
+            trace_id: String::from(trace_id),
+            span_id: String::from(span_id),
+            flags: u8::from_str_radix(flags, 16).ok()?,
+            tracestate: tracestate.map(String::from),
+        })
+    }
+
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let get = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        Self::parse(get("traceparent")?, get("tracestate"))
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{:02x}", self.trace_id, self.span_id, self.flags)
+    }
+
+    /// The context of the request currently being handled, if any.
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(Clone::clone).ok()
+    }
+
+    /// Runs `fut` with `self` as [`TraceContext::current`], so outgoing calls continue the trace.
+    pub async fn scope<F: Future>(self, fut: F) -> F::Output {
+        CURRENT.scope(self, fut).await
+    }
+}
+
+fn new_span_id() -> String {
+    format!("{:016x}", rand::random::<u64>().max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::{call, testing};
+
+    #[test]
+    fn parses_traceparent() {
+        let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let ctx = TraceContext::parse(header, None).unwrap();
+        assert_eq!(ctx.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(ctx.traceparent(), header);
+        assert!(TraceContext::parse(
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            None
+        )
+        .is_none());
+    }
+
+    #[tokio::test]
+    async fn trace_id_propagates_from_client_to_server() {
+        let addr = testing::serve(Default::default()).await;
+        let ctx = TraceContext::new_root();
+        let trace_id = ctx.trace_id.clone();
+
+        let url = format!("http://{addr}");
+        let call = call::<_, Value>(&url, "trace", &(), None);
+        let r = ctx.scope(call).await.unwrap();
+        assert_eq!(r, Some(Value::from(trace_id)));
+    }
+}
@@ -1,7 +1,204 @@
+use std::{
+    fmt,
+    hash::{Hash, Hasher},
+};
+
 use anyhow::Result;
-use serde::{Deserialize, Serialize};
+use hyper::StatusCode;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::Value;
 
+use crate::json;
+
+/// A request id, restricted to what the spec allows: a number, a string or null.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub enum RpcId {
+    Number(i64),
+    /// A number with a fractional part or an exponent, which the spec discourages, see
+    /// [`ServerConfig::reject_fractional_ids`](crate::ServerConfig::reject_fractional_ids).
+    Fractional(FractionalId),
+    String(String),
+    #[default]
+    Null,
+}
+
+/// The finite `f64` of an [`RpcId::Fractional`]. Ids compare by value, with `-0.0`
+/// equal to `0.0`, and NaN and infinities can't be built, so ids stay usable as keys.
+#[derive(Debug, Clone, Copy)]
+pub struct FractionalId(f64);
+
+impl FractionalId {
+    /// `None` unless `v` is finite, as JSON has no NaN or infinities.
+    pub fn new(v: f64) -> Option<Self> {
+        // Adding zero turns -0.0 into 0.0, so equal ids have the same bits.
+        v.is_finite().then_some(Self(v + 0.0))
+    }
+
+    pub fn get(self) -> f64 {
+        self.0
+    }
+}
+
+impl PartialEq for FractionalId {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.to_bits() == other.0.to_bits()
+    }
+}
+
+impl Eq for FractionalId {}
+
+impl Hash for FractionalId {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.to_bits().hash(state);
+    }
+}
+
+impl RpcId {
+    pub fn is_null(&self) -> bool {
+        matches!(self, Self::Null)
+    }
+}
+
+impl fmt::Display for RpcId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Number(n) => write!(f, "{}", n),
+            Self::Fractional(n) => write!(f, "{}", n.get()),
+            Self::String(s) => write!(f, "{:?}", s),
+            Self::Null => write!(f, "null"),
+        }
+    }
+}
+
+impl From<i64> for RpcId {
+    fn from(v: i64) -> Self {
+        Self::Number(v)
+    }
+}
+
+impl From<i32> for RpcId {
+    fn from(v: i32) -> Self {
+        Self::Number(v.into())
+    }
+}
+
+impl From<u32> for RpcId {
+    fn from(v: u32) -> Self {
+        Self::Number(v.into())
+    }
+}
+
+impl From<&str> for RpcId {
+    fn from(v: &str) -> Self {
+        Self::String(String::from(v))
+    }
+}
+
+impl From<String> for RpcId {
+    fn from(v: String) -> Self {
+        Self::String(v)
+    }
+}
+
+impl Serialize for RpcId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            Self::Number(n) => serializer.serialize_i64(*n),
+            Self::Fractional(n) => serializer.serialize_f64(n.get()),
+            Self::String(s) => serializer.serialize_str(s),
+            Self::Null => serializer.serialize_unit(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for RpcId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct IdVisitor;
+
+        impl<'de> de::Visitor<'de> for IdVisitor {
+            type Value = RpcId;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a number, a string or null")
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> std::result::Result<RpcId, E> {
+                Ok(RpcId::Number(v))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> std::result::Result<RpcId, E> {
+                i64::try_from(v)
+                    .map(RpcId::Number)
+                    .map_err(|_| E::invalid_value(de::Unexpected::Unsigned(v), &self))
+            }
+
+            fn visit_f64<E: de::Error>(self, v: f64) -> std::result::Result<RpcId, E> {
+                FractionalId::new(v)
+                    .map(RpcId::Fractional)
+                    .ok_or_else(|| E::invalid_value(de::Unexpected::Float(v), &self))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<RpcId, E> {
+                Ok(RpcId::from(v))
+            }
+
+            fn visit_string<E: de::Error>(self, v: String) -> std::result::Result<RpcId, E> {
+                Ok(RpcId::String(v))
+            }
+
+            fn visit_unit<E: de::Error>(self) -> std::result::Result<RpcId, E> {
+                Ok(RpcId::Null)
+            }
+
+            fn visit_none<E: de::Error>(self) -> std::result::Result<RpcId, E> {
+                Ok(RpcId::Null)
+            }
+        }
+
+        deserializer.deserialize_any(IdVisitor)
+    }
+}
+
+/// The shape of a structurally valid request body, see [`validate_request`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    Single,
+    Batch(usize),
+}
+
+/// Checks that `value` is a JSON-RPC 2.0 request or a non-empty batch, without looking
+/// at any method's params. Batch entries are left to be checked one by one.
+pub fn validate_request(value: &Value) -> std::result::Result<RequestKind, RPCError> {
+    let invalid = |msg: &str| RPCError {
+        data: Some(String::from(msg)),
+        ..RPCError::invalid_request()
+    };
+    match value {
+        Value::Array(entries) if entries.is_empty() => Err(invalid("empty batch")),
+        Value::Array(entries) => Ok(RequestKind::Batch(entries.len())),
+        Value::Object(req) => {
+            if req.get("jsonrpc").and_then(Value::as_str) != Some("2.0") {
+                return Err(invalid("jsonrpc must be \"2.0\""));
+            }
+            if !req.get("method").is_some_and(Value::is_string) {
+                return Err(invalid("method must be a string"));
+            }
+            if req
+                .get("params")
+                .is_some_and(|p| !(p.is_array() || p.is_object() || p.is_null()))
+            {
+                return Err(invalid("params must be an array or an object"));
+            }
+            if let Some(id) = req.get("id") {
+                RpcId::deserialize(id)
+                    .map_err(|_| invalid("id must be a string, a number or null"))?;
+            }
+            Ok(RequestKind::Single)
+        }
+        _ => Err(invalid("request must be an object or an array")),
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RPCRequest<T>
 where
@@ -9,8 +206,24 @@ where
 {
     pub jsonrpc: String,
     pub method: String,
+    /// Left out of the JSON when it serializes as `None` or `()`.
+    #[serde(skip_serializing_if = "json::is_absent")]
     pub params: T,
-    pub id: Value,
+    /// `None` makes this a notification, serialized without an `id` key at all;
+    /// `Some(RpcId::Null)` is sent as `"id": null`.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "present_id"
+    )]
+    pub id: Option<RpcId>,
+}
+
+/// Keeps an explicit `"id": null` apart from a missing id.
+fn present_id<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<RpcId>, D::Error> {
+    RpcId::deserialize(deserializer).map(Some)
 }
 
 impl<T> RPCRequest<T>
@@ -22,73 +235,245 @@ where
             jsonrpc: String::from("2.0"),
             method: String::from(method),
             params,
-            id: Value::from(1),
+            id: Some(RpcId::Number(1)),
         }
     }
+
+    /// A request the server is not expected to answer.
+    pub fn notification(method: &str, params: T) -> Self {
+        Self {
+            id: None,
+            ..Self::new(method, params)
+        }
+    }
+
+    pub fn is_notification(&self) -> bool {
+        self.id.is_none()
+    }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+impl<T> RPCRequest<Option<T>>
+where
+    T: Clone,
+{
+    /// A request sent without any `params` member.
+    pub fn new_no_params(method: &str) -> Self {
+        Self::new(method, None)
+    }
+}
+
+/// Either the result of a call or its error, never both.
+#[derive(Debug, Clone)]
+pub enum ResponsePayload<T> {
+    Result(Option<T>),
+    Error(RPCError),
+}
+
+/// Serialized with its keys in a fixed order: `jsonrpc`, then `result` or `error`
+/// (itself `code`, `message`, `data`), then `id`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "RPCResponseRaw<T>")]
 pub struct RPCResponse<T> {
     pub jsonrpc: String,
-    pub result: Option<T>,
-    pub error: Option<RPCError>,
-    pub id: Value,
+    pub payload: ResponsePayload<T>,
+    pub id: RpcId,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct RPCResponseResult<T> {
-    pub jsonrpc: String,
-    pub result: Option<T>,
-    pub id: Value,
+#[derive(Serialize)]
+struct RPCResponseResult<'a, T> {
+    jsonrpc: &'a str,
+    result: &'a Option<T>,
+    id: &'a RpcId,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct RPCResponseError {
-    pub jsonrpc: String,
-    pub error: RPCError,
-    pub id: Value,
+#[derive(Serialize)]
+struct RPCResponseError<'a, E> {
+    jsonrpc: &'a str,
+    error: E,
+    id: &'a RpcId,
+}
+
+/// An [`RPCError`] serialized with `"data": null` instead of omitting it.
+#[derive(Serialize)]
+struct RPCErrorWithData<'a> {
+    code: i32,
+    message: &'a str,
+    data: &'a Option<String>,
+}
+
+/// Serializes like [`RPCResponse`], but with every error's `data` present.
+struct WithErrorData<'a, T>(&'a RPCResponse<T>);
+
+impl<T: Serialize> Serialize for WithErrorData<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match &self.0.payload {
+            ResponsePayload::Error(error) => RPCResponseError {
+                jsonrpc: &self.0.jsonrpc,
+                error: RPCErrorWithData {
+                    code: error.code,
+                    message: &error.message,
+                    data: &error.data,
+                },
+                id: &self.0.id,
+            }
+            .serialize(serializer),
+            ResponsePayload::Result(_) => self.0.serialize(serializer),
+        }
+    }
+}
+
+/// What is accepted on the wire; an `error` wins over any `result` next to it.
+#[derive(Deserialize)]
+struct RPCResponseRaw<T> {
+    jsonrpc: String,
+    result: Option<T>,
+    error: Option<RPCError>,
+    id: RpcId,
+}
+
+impl<T> From<RPCResponseRaw<T>> for RPCResponse<T> {
+    fn from(r: RPCResponseRaw<T>) -> Self {
+        let payload = match r.error {
+            Some(e) => ResponsePayload::Error(e),
+            None => ResponsePayload::Result(r.result),
+        };
+        Self {
+            jsonrpc: r.jsonrpc,
+            payload,
+            id: r.id,
+        }
+    }
+}
+
+impl<T: Serialize> Serialize for RPCResponse<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match &self.payload {
+            ResponsePayload::Result(result) => RPCResponseResult {
+                jsonrpc: &self.jsonrpc,
+                result,
+                id: &self.id,
+            }
+            .serialize(serializer),
+            ResponsePayload::Error(error) => RPCResponseError {
+                jsonrpc: &self.jsonrpc,
+                error,
+                id: &self.id,
+            }
+            .serialize(serializer),
+        }
+    }
 }
 
 impl<T> RPCResponse<T> {
-    pub fn result(id: Value, t: Option<T>) -> Self {
+    pub fn result(id: RpcId, t: Option<T>) -> Self {
         Self {
             jsonrpc: String::from("2.0"),
-            result: t,
-            error: None,
+            payload: ResponsePayload::Result(t),
             id,
         }
     }
 
-    pub fn error(id: Value, e: RPCError) -> Self {
+    pub fn error(id: RpcId, e: RPCError) -> Self {
         Self {
             jsonrpc: String::from("2.0"),
-            result: None,
-            error: Some(e),
+            payload: ResponsePayload::Error(e),
             id,
         }
     }
+
+    pub fn from_result(id: RpcId, result: RPCResult<Option<T>>) -> Self {
+        match result {
+            Ok(v) => Self::result(id, v),
+            Err(e) => Self::error(id, e),
+        }
+    }
+
+    /// The result, `None` when it is null or the call failed.
+    pub fn as_result(&self) -> Option<&T> {
+        match &self.payload {
+            ResponsePayload::Result(r) => r.as_ref(),
+            ResponsePayload::Error(_) => None,
+        }
+    }
+
+    pub fn as_error(&self) -> Option<&RPCError> {
+        match &self.payload {
+            ResponsePayload::Result(_) => None,
+            ResponsePayload::Error(e) => Some(e),
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        matches!(self.payload, ResponsePayload::Error(_))
+    }
+
+    /// Collapses the envelope into what the call returned.
+    pub fn into_result(self) -> RPCResult<Option<T>> {
+        match self.payload {
+            ResponsePayload::Result(r) => Ok(r),
+            ResponsePayload::Error(e) => Err(e),
+        }
+    }
+
+    /// Like [`RPCResponse::into_result`], but a null result is an error too.
+    ///
+    /// Coherence rules forbid `TryInto<T>` for any `T`, so only
+    /// `RPCResponse<Value>` also converts with `try_into`.
+    pub fn try_into_result(self) -> RPCResult<T> {
+        self.into_result()?
+            .ok_or_else(|| RPCError::internal_error(String::from("Response has a null result")))
+    }
+}
+
+impl TryFrom<RPCResponse<Value>> for Value {
+    type Error = RPCError;
+
+    fn try_from(resp: RPCResponse<Value>) -> RPCResult<Self> {
+        resp.try_into_result()
+    }
 }
+
 impl<T> RPCResponse<T>
 where
     T: Serialize,
 {
     pub fn into_value(self) -> Result<Value> {
-        if let Some(e) = self.error {
-            let v = RPCResponseError {
-                id: self.id,
-                jsonrpc: self.jsonrpc,
-                error: e,
-            };
-            Ok(serde_json::to_value(v)?)
-        } else {
-            let v = RPCResponseResult {
-                id: self.id,
-                jsonrpc: self.jsonrpc,
-                result: self.result,
-            };
+        Ok(serde_json::to_value(&self)?)
+    }
 
-            Ok(serde_json::to_value(v)?)
-        }
+    /// Serializes straight to a string, so `Box<RawValue>` results keep their exact bytes.
+    pub fn into_json(self) -> Result<String> {
+        Ok(serde_json::to_string(&self)?)
+    }
+
+    /// Like [`RPCResponse::into_json`], but writes `"data": null` into errors without data.
+    pub fn into_json_with_error_data(self) -> Result<String> {
+        Ok(serde_json::to_string(&WithErrorData(&self))?)
+    }
+
+    /// Serializes like this response, with `"data": null` in errors without data.
+    pub(crate) fn with_error_data(&self) -> impl Serialize + '_ {
+        WithErrorData(self)
+    }
+
+    /// Turns the result into a [`Value`], whose objects always serialize with sorted
+    /// keys, so equal responses give equal bytes whatever map types `T` contains.
+    pub fn canonical(self) -> Result<RPCResponse<Value>> {
+        let payload = match self.payload {
+            ResponsePayload::Result(r) => {
+                ResponsePayload::Result(r.map(serde_json::to_value).transpose()?)
+            }
+            ResponsePayload::Error(e) => ResponsePayload::Error(e),
+        };
+        Ok(RPCResponse {
+            jsonrpc: self.jsonrpc,
+            payload,
+            id: self.id,
+        })
+    }
+
+    pub fn batch_into_json(responses: Vec<Self>) -> Result<String> {
+        Ok(serde_json::to_string(&responses)?)
     }
 }
 
@@ -101,6 +486,30 @@ pub struct RPCError {
 }
 
 impl RPCError {
+    /// The HTTP status an HTTP-aware gateway would answer this error with.
+    ///
+    /// Parse and request errors map to 400, unknown methods to 404, `unauthorized` to 401,
+    /// `server_busy` and `temporary_failure` to 503, `request_timeout` to 504, everything
+    /// else, `response_too_large` included, to 500.
+    pub fn http_status(&self) -> StatusCode {
+        match self.code {
+            -32700 | -32600 | -32602 => StatusCode::BAD_REQUEST,
+            -32601 => StatusCode::NOT_FOUND,
+            -32001 => StatusCode::UNAUTHORIZED,
+            -32003 => StatusCode::FORBIDDEN,
+            -32000 | -32010 => StatusCode::SERVICE_UNAVAILABLE,
+            -32002 => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Replaces the message text while keeping the code, e.g.
+    /// `RPCError::unknown_method().with_message("no such method: foo")`.
+    pub fn with_message(mut self, message: &str) -> Self {
+        self.message = String::from(message);
+        self
+    }
+
     pub fn unknown_method() -> Self {
         RPCError {
             code: -32601,
@@ -117,6 +526,14 @@ impl RPCError {
         }
     }
 
+    pub fn invalid_request() -> Self {
+        Self {
+            code: -32600,
+            message: String::from("Invalid Request"),
+            data: None,
+        }
+    }
+
     pub fn invalid_params() -> Self {
         Self {
             code: -32602,
@@ -125,6 +542,71 @@ impl RPCError {
         }
     }
 
+    pub fn server_busy() -> Self {
+        Self {
+            code: -32000,
+            message: String::from("Server busy"),
+            data: None,
+        }
+    }
+
+    pub fn temporary_failure() -> Self {
+        Self {
+            code: -32010,
+            message: String::from("Temporary failure"),
+            data: None,
+        }
+    }
+
+    /// Replaces a response over `ServerConfig::max_response_bytes`.
+    ///
+    /// `-32004` rather than `-32000`: that code is [`RPCError::server_busy`], which
+    /// clients take as an overload worth retrying (see `RetryPolicy::codes`), while
+    /// a response this large comes back just as large next time. It maps to HTTP 500,
+    /// not 503, for the same reason.
+    pub fn response_too_large() -> Self {
+        Self {
+            code: -32004,
+            message: String::from("Response too large"),
+            data: None,
+        }
+    }
+
+    /// Answers a call that ran out of time, see `ServerConfig::request_timeout`.
+    pub fn request_timeout() -> Self {
+        Self {
+            code: -32002,
+            message: String::from("Request timed out"),
+            data: None,
+        }
+    }
+
+    /// Answers a request its client cancelled, e.g. with `rpc.cancel`.
+    pub fn request_cancelled() -> Self {
+        Self {
+            code: -32800,
+            message: String::from("Request cancelled"),
+            data: None,
+        }
+    }
+
+    pub fn unauthorized() -> Self {
+        Self {
+            code: -32001,
+            message: String::from("Unauthorized"),
+            data: None,
+        }
+    }
+
+    /// Denies an authenticated caller a method, see `Authorizer`.
+    pub fn forbidden() -> Self {
+        Self {
+            code: -32003,
+            message: String::from("Forbidden"),
+            data: None,
+        }
+    }
+
     pub fn internal_error(data: String) -> Self {
         Self {
             code: -32603,
@@ -135,3 +617,162 @@ impl RPCError {
 }
 
 pub type RPCResult<T> = std::result::Result<T, RPCError>;
+
+/// Maps a domain error onto the [`RPCError`] clients see.
+///
+/// Implementing it also makes `?` convert the error inside handlers. There is no
+/// blanket impl for every `Display` type, as it would forbid custom mappings for
+/// them; wrap such errors with [`RPCError::internal_error`] instead.
+pub trait IntoRpcError {
+    fn into_rpc_error(self) -> RPCError;
+}
+
+impl<E: IntoRpcError> From<E> for RPCError {
+    fn from(e: E) -> Self {
+        e.into_rpc_error()
+    }
+}
+
+impl IntoRpcError for anyhow::Error {
+    fn into_rpc_error(self) -> RPCError {
+        RPCError::internal_error(format!("{self:?}"))
+    }
+}
+
+impl IntoRpcError for serde_json::Error {
+    fn into_rpc_error(self) -> RPCError {
+        RPCError::internal_error(format!("{self:?}"))
+    }
+}
+
+impl IntoRpcError for std::io::Error {
+    fn into_rpc_error(self) -> RPCError {
+        RPCError::internal_error(format!("{self:?}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn response_too_large_is_not_an_overload() {
+        let retried = crate::RetryPolicy::default().codes;
+        assert!(!retried.contains(&RPCError::response_too_large().code));
+        assert_eq!(
+            RPCError::response_too_large().http_status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(
+            RPCError::server_busy().http_status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            RPCError::temporary_failure().http_status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[test]
+    fn responses_serialize_in_a_fixed_order() {
+        // A `HashMap` iterates in any order; canonical results sort their keys.
+        let mut result = std::collections::HashMap::new();
+        result.insert("b", 2);
+        result.insert("a", 1);
+        let ok = RPCResponse::result(RpcId::from("x"), Some(result));
+        assert_eq!(
+            ok.canonical().unwrap().into_json().unwrap(),
+            r#"{"jsonrpc":"2.0","result":{"a":1,"b":2},"id":"x"}"#
+        );
+
+        let e = RPCError {
+            data: Some(String::from("why")),
+            ..RPCError::invalid_params()
+        };
+        let failed = RPCResponse::<()>::error(RpcId::from(7), e);
+        assert_eq!(
+            failed.into_json().unwrap(),
+            r#"{"jsonrpc":"2.0","error":{"code":-32602,"message":"Invalid params","data":"why"},"id":7}"#
+        );
+        let empty = RPCResponse::<()>::result(RpcId::Null, None);
+        assert_eq!(
+            empty.into_json().unwrap(),
+            r#"{"jsonrpc":"2.0","result":null,"id":null}"#
+        );
+    }
+
+    #[test]
+    fn fractional_ids_are_finite_keys() {
+        assert!(FractionalId::new(f64::NAN).is_none());
+        assert!(FractionalId::new(f64::INFINITY).is_none());
+
+        let id: RpcId = serde_json::from_str("1.5").unwrap();
+        assert_eq!(id, RpcId::Fractional(FractionalId::new(1.5).unwrap()));
+        assert_eq!(serde_json::to_string(&id).unwrap(), "1.5");
+
+        let zero = RpcId::Fractional(FractionalId::new(0.0).unwrap());
+        let negative_zero = RpcId::Fractional(FractionalId::new(-0.0).unwrap());
+        assert_eq!(zero, negative_zero);
+        let ids = std::collections::HashSet::from([zero, negative_zero]);
+        assert_eq!(ids.len(), 1);
+    }
+
+    #[test]
+    fn error_data_written_as_null_on_request() {
+        let resp = || RPCResponse::<()>::error(RpcId::from(1), RPCError::unknown_method());
+        let omitted: Value = serde_json::from_str(&resp().into_json().unwrap()).unwrap();
+        assert!(omitted["error"].get("data").is_none());
+        let present: Value =
+            serde_json::from_str(&resp().into_json_with_error_data().unwrap()).unwrap();
+        assert_eq!(present["error"].get("data"), Some(&Value::Null));
+
+        let data = RPCResponse::<()>::error(RpcId::from(1), RPCError::internal_error("x".into()));
+        let kept: Value = serde_json::from_str(&data.into_json_with_error_data().unwrap()).unwrap();
+        assert_eq!(kept["error"]["data"], "x");
+        let result = RPCResponse::result(RpcId::from(1), Some(1));
+        assert!(!result.into_json_with_error_data().unwrap().contains("data"));
+    }
+
+    #[test]
+    fn requests_without_params_leave_the_key_out() {
+        let req = RPCRequest::<Option<Value>>::new_no_params("ping");
+        assert_eq!(
+            serde_json::to_string(&req).unwrap(),
+            r#"{"jsonrpc":"2.0","method":"ping","id":1}"#
+        );
+        let unit = RPCRequest::notification("ping", ());
+        assert_eq!(
+            serde_json::to_string(&unit).unwrap(),
+            r#"{"jsonrpc":"2.0","method":"ping"}"#
+        );
+        let empty = RPCRequest::new("ping", Some(Value::Array(vec![])));
+        assert!(serde_json::to_string(&empty)
+            .unwrap()
+            .contains(r#""params":[]"#));
+    }
+
+    #[test]
+    fn responses_collapse_into_what_the_call_returned() {
+        let ok = || RPCResponse::result(RpcId::from(1), Some(json!([1])));
+        let failed = || RPCResponse::<Value>::error(RpcId::from(1), RPCError::server_busy());
+        let null = || RPCResponse::<Value>::result(RpcId::from(1), None);
+
+        assert_eq!(ok().into_result().unwrap(), Some(json!([1])));
+        assert_eq!(ok().try_into_result().unwrap(), json!([1]));
+        assert_eq!(Value::try_from(ok()).unwrap(), json!([1]));
+
+        let code = RPCError::server_busy().code;
+        assert_eq!(failed().into_result().unwrap_err().code, code);
+        assert_eq!(failed().try_into_result().unwrap_err().code, code);
+        assert_eq!(Value::try_from(failed()).unwrap_err().code, code);
+
+        assert_eq!(null().into_result().unwrap(), None);
+        let missing = null().try_into_result().unwrap_err();
+        assert_eq!(missing.code, RPCError::internal_error(String::new()).code);
+        assert_eq!(missing.data.as_deref(), Some("Response has a null result"));
+        let converted: RPCResult<Value> = null().try_into();
+        assert!(converted.is_err());
+    }
+}
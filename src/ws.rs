@@ -0,0 +1,1052 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use futures::{future::BoxFuture, Sink, SinkExt, StreamExt};
+use hyper::{header, upgrade::Upgraded, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::{
+    sync::{broadcast, mpsc},
+    task::{AbortHandle, JoinHandle},
+    time::Instant,
+};
+use tokio_tungstenite::{
+    tungstenite::{self, handshake::derive_accept_key, protocol::Role, Message},
+    WebSocketStream,
+};
+
+use crate::{
+    server::{
+        _batch_handle, _handle, authorize, encode, full_body, request_id, InFlightGuard,
+        ServerState,
+    },
+    Handle, RPCError, RPCRequest, RPCResponse, RPCResult, RequestContext, ResponseBody, RpcId,
+    ServerConfig, ServerEvent,
+};
+
+/// Frames queued for a connection before its writer stops taking them.
+const WRITE_QUEUE: usize = 64;
+
+/// Queues frames for the one task writing to a connection, so frames of calls,
+/// subscriptions and broadcasts are written whole and one after another.
+#[derive(Clone)]
+struct WsSink(mpsc::Sender<Message>);
+
+impl WsSink {
+    async fn send(&self, text: String) -> Result<()> {
+        self.send_frame(Message::Text(text.into())).await
+    }
+
+    async fn send_frame(&self, frame: Message) -> Result<()> {
+        self.0
+            .send(frame)
+            .await
+            .map_err(|_| anyhow!("WebSocket connection closed"))
+    }
+}
+
+/// When to ping a WebSocket peer, and when to give up on one that stopped answering.
+/// Any frame from the peer counts as an answer.
+pub(crate) struct Keepalive {
+    interval: Option<Duration>,
+    timeout: Duration,
+    next_ping: Instant,
+    pong_due: Option<Instant>,
+}
+
+impl Keepalive {
+    pub(crate) fn new(interval: Option<Duration>, timeout: Duration) -> Self {
+        Self {
+            interval,
+            timeout,
+            next_ping: Instant::now() + interval.unwrap_or_default(),
+            pong_due: None,
+        }
+    }
+
+    /// Resolves to `true` when it is time to ping, `false` once the peer missed a pong.
+    /// Never resolves without an interval. Cancelling it changes nothing.
+    pub(crate) async fn tick(&mut self) -> bool {
+        let Some(interval) = self.interval else {
+            return std::future::pending().await;
+        };
+        if let Some(due) = self.pong_due {
+            tokio::time::sleep_until(due).await;
+            return false;
+        }
+        tokio::time::sleep_until(self.next_ping).await;
+        let now = Instant::now();
+        self.next_ping = now + interval;
+        self.pong_due = Some(now + self.timeout);
+        true
+    }
+
+    pub(crate) fn heard(&mut self) {
+        if let Some(interval) = self.interval {
+            self.next_ping = Instant::now() + interval;
+            self.pong_due = None;
+        }
+    }
+}
+
+/// Writes queued frames until the connection fails or every [`WsSink`] is gone.
+async fn write_frames<S>(mut sink: S, mut frames: mpsc::Receiver<Message>)
+where
+    S: Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+    while let Some(frame) = frames.recv().await {
+        if let Err(e) = sink.send(frame).await {
+            log::debug!("WebSocket connection error: {}", e);
+            break;
+        }
+    }
+}
+
+/// Pushes notifications for one subscription to its WebSocket connection.
+#[derive(Clone)]
+pub struct SubscriptionSink {
+    id: u64,
+    method: String,
+    sink: WsSink,
+}
+
+impl SubscriptionSink {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Sends `{"method": <subscribe method>, "params": {"subscription": id, "result": result}}`.
+    pub async fn notify<T: Serialize>(&self, result: &T) -> Result<()> {
+        let msg = json!({
+            "jsonrpc": "2.0",
+            "method": self.method,
+            "params": { "subscription": self.id, "result": result },
+        });
+        self.sink.send(msg.to_string()).await
+    }
+}
+
+/// Pushes follow-ups of one request to its WebSocket connection.
+#[derive(Clone)]
+pub struct FollowUpSink {
+    id: RpcId,
+    method: String,
+    sink: WsSink,
+}
+
+impl FollowUpSink {
+    /// The id of the request these follow-ups belong to.
+    pub fn id(&self) -> &RpcId {
+        &self.id
+    }
+
+    /// Sends `{"method": <request method>, "params": {"id": request id, "result": result}}`.
+    ///
+    /// Follow-ups are notifications, so the request's own id only appears in `params`
+    /// and clients never mistake them for a second response.
+    pub async fn notify<T: Serialize>(&self, result: &T) -> Result<()> {
+        let msg = json!({
+            "jsonrpc": "2.0",
+            "method": self.method,
+            "params": { "id": self.id, "result": result },
+        });
+        self.sink.send(msg.to_string()).await
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Broadcast {
+    method: String,
+    params: Value,
+}
+
+/// Fans notifications out to every WebSocket connection listening on their method.
+///
+/// Clients start and stop listening with `rpc.on` / `rpc.off` and a list of methods.
+#[derive(Debug, Clone)]
+pub struct Broadcaster {
+    tx: broadcast::Sender<Arc<Broadcast>>,
+}
+
+impl Broadcaster {
+    /// `capacity` notifications are buffered per connection before slow ones start missing them.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            tx: broadcast::channel(capacity).0,
+        }
+    }
+
+    /// Returns how many connections may receive the notification.
+    pub fn send<T: Serialize>(&self, method: &str, params: &T) -> Result<usize> {
+        let b = Broadcast {
+            method: String::from(method),
+            params: serde_json::to_value(params)?,
+        };
+        Ok(self.tx.send(Arc::new(b)).unwrap_or(0))
+    }
+}
+
+impl Default for Broadcaster {
+    fn default() -> Self {
+        Self::new(1024)
+    }
+}
+
+/// Calls of one connection still running, by request id.
+type Calls = Arc<std::sync::Mutex<HashMap<RpcId, AbortHandle>>>;
+
+/// The producer and call tasks of one connection; dropping it cancels all of them.
+struct Subscriptions {
+    next_id: u64,
+    tasks: HashMap<u64, JoinHandle<()>>,
+    topics: Arc<std::sync::Mutex<HashSet<String>>>,
+    forwarder: Option<JoinHandle<()>>,
+    calls: Calls,
+    /// Where calls count as in flight, next to the server's HTTP requests.
+    state: Arc<ServerState>,
+}
+
+impl Subscriptions {
+    fn new(state: Arc<ServerState>) -> Self {
+        Self {
+            next_id: 0,
+            tasks: HashMap::new(),
+            topics: Arc::default(),
+            forwarder: None,
+            calls: Calls::default(),
+            state,
+        }
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn insert(&mut self, id: u64, producer: BoxFuture<'static, ()>) {
+        self.tasks.retain(|_, t| !t.is_finished());
+        self.tasks.insert(id, tokio::spawn(producer));
+    }
+
+    fn remove(&mut self, id: u64) -> bool {
+        self.tasks.remove(&id).map(|t| t.abort()).is_some()
+    }
+
+    /// Answers the call from its own task, cancellable by `id` until it finishes.
+    /// Refuses it while another call with the same id runs, or like an HTTP request
+    /// over [`ServerConfig::max_in_flight`].
+    fn spawn_call(
+        &mut self,
+        id: Option<RpcId>,
+        reply: BoxFuture<'static, Result<Option<String>>>,
+        sink: &WsSink,
+        config: &Arc<ServerConfig>,
+    ) -> RPCResult<()> {
+        let (sink, config, calls) = (sink.clone(), config.clone(), self.calls.clone());
+        // Held until the call is registered, so it can't finish before that.
+        let mut running = self.calls.lock().unwrap();
+        if id.as_ref().is_some_and(|id| running.contains_key(id)) {
+            return Err(
+                RPCError::invalid_request().with_message("Duplicate id of a call in flight")
+            );
+        }
+        let guard = InFlightGuard::new(self.state.clone());
+        if let Some(e) = self.state.refusal(&config, guard.current) {
+            guard.finish();
+            return Err(e);
+        }
+        let task_id = id.clone();
+        let task = tokio::spawn(async move {
+            let reply = reply.await.unwrap_or_else(|e| {
+                log::debug!("Failed to handle WebSocket message: {:?}", e);
+                let r = RPCResponse::<()>::error(RpcId::Null, RPCError::parse_error());
+                encode(r, &config).ok()
+            });
+            if let Some(id) = task_id {
+                let mut calls = calls.lock().unwrap();
+                // Gone means `rpc.cancel` took it and has answered for it already.
+                if calls.get(&id).is_none_or(|t| t.id() != tokio::task::id()) {
+                    return;
+                }
+                calls.remove(&id);
+            }
+            guard.finish();
+            let Some(reply) = reply else {
+                return;
+            };
+            if let Err(e) = sink.send(reply).await {
+                log::debug!("{}", e);
+            }
+        });
+        if let Some(id) = id {
+            running.insert(id, task.abort_handle());
+        }
+        Ok(())
+    }
+
+    fn cancel(&mut self, id: &RpcId) -> bool {
+        self.calls
+            .lock()
+            .unwrap()
+            .remove(id)
+            .map(|t| t.abort())
+            .is_some()
+    }
+
+    fn listen(&mut self, broadcaster: &Broadcaster, sink: &WsSink, methods: Vec<String>) {
+        self.topics.lock().unwrap().extend(methods);
+        if self.forwarder.is_some() {
+            return;
+        }
+
+        let mut rx = broadcaster.tx.subscribe();
+        let topics = self.topics.clone();
+        let sink = sink.clone();
+        self.forwarder = Some(tokio::spawn(async move {
+            loop {
+                let b = match rx.recv().await {
+                    Ok(b) => b,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        log::warn!("WebSocket connection missed {} broadcasts", n);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                if !topics.lock().unwrap().contains(&b.method) {
+                    continue;
+                }
+                let msg = json!({ "jsonrpc": "2.0", "method": b.method, "params": b.params });
+                if sink.send(msg.to_string()).await.is_err() {
+                    break;
+                }
+            }
+        }));
+    }
+
+    fn unlisten(&mut self, methods: &[String]) {
+        let mut topics = self.topics.lock().unwrap();
+        for m in methods {
+            topics.remove(m);
+        }
+    }
+}
+
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        for (_, task) in self.tasks.drain() {
+            task.abort();
+        }
+        if let Some(task) = self.forwarder.take() {
+            task.abort();
+        }
+        for (_, task) in self.calls.lock().unwrap().drain() {
+            task.abort();
+        }
+    }
+}
+
+pub(crate) fn is_upgrade<B>(request: &Request<B>) -> bool {
+    request
+        .headers()
+        .get(header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+}
+
+pub(crate) fn upgrade<H, B>(
+    mut request: Request<B>,
+    handle: Arc<H>,
+    config: Arc<ServerConfig>,
+    ctx: RequestContext,
+    state: Arc<ServerState>,
+) -> Result<Response<ResponseBody>>
+where
+    H: Handle + Send + Sync + 'static,
+    H::Request: Debug,
+{
+    let key = request
+        .headers()
+        .get(header::SEC_WEBSOCKET_KEY)
+        .ok_or_else(|| anyhow!("Missing Sec-WebSocket-Key"))?;
+    let accept = derive_accept_key(key.as_bytes());
+
+    let on_upgrade = hyper::upgrade::on(&mut request);
+    let spawner = config.clone();
+    spawner.spawn(async move {
+        match on_upgrade.await {
+            Ok(upgraded) => {
+                let io = TokioIo::new(upgraded);
+                let ws = WebSocketStream::from_raw_socket(io, Role::Server, None).await;
+                ctx.scope(serve_socket(ws, handle, config, state)).await;
+            }
+            Err(e) => log::error!("WebSocket upgrade failed: {:?}", e),
+        }
+    });
+
+    Ok(Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "Upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_ACCEPT, accept)
+        .body(full_body(Bytes::new()))?)
+}
+
+async fn serve_socket<H>(
+    ws: WebSocketStream<TokioIo<Upgraded>>,
+    handle: Arc<H>,
+    config: Arc<ServerConfig>,
+    state: Arc<ServerState>,
+) where
+    H: Handle + Send + Sync + 'static,
+    H::Request: Debug,
+{
+    let (writer, mut stream) = ws.split();
+    let (frames, queued) = mpsc::channel(WRITE_QUEUE);
+    let writer = tokio::spawn(write_frames(writer, queued));
+    let sink = WsSink(frames);
+    let mut subscriptions = Subscriptions::new(state);
+    let mut keepalive = Keepalive::new(config.ws_ping_interval, config.ws_pong_timeout);
+
+    loop {
+        let msg = tokio::select! {
+            msg = stream.next() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            ping = keepalive.tick() => {
+                if !ping {
+                    log::debug!("WebSocket client stopped answering pings, closing");
+                    break;
+                }
+                if sink.send_frame(Message::Ping(Bytes::new())).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
+        keepalive.heard();
+        let text = match msg {
+            Ok(Message::Text(t)) => t,
+            Ok(Message::Close(_)) => break,
+            Ok(_) => continue,
+            Err(e) => {
+                log::debug!("WebSocket connection error: {}", e);
+                break;
+            }
+        };
+
+        let (reply, producer) =
+            match dispatch(&text, &handle, &config, &sink, &mut subscriptions).await {
+                Ok(Reply::Now(reply, producer)) => (reply, producer),
+                Ok(Reply::Nothing) => continue,
+                Ok(Reply::Later(id, reply)) => {
+                    let e = match subscriptions.spawn_call(id.clone(), reply, &sink, &config) {
+                        Ok(()) => continue,
+                        Err(e) => e,
+                    };
+                    // Notifications get no answer, refused or not.
+                    let Some(id) = id else {
+                        log::debug!("Dropped WebSocket notification: {}", e.message);
+                        continue;
+                    };
+                    let r = encode(RPCResponse::<()>::error(id, e), &config);
+                    (r.unwrap_or_default(), None)
+                }
+                Err(e) => {
+                    log::debug!("Failed to handle WebSocket message: {:?}", e);
+                    let r = encode(
+                        RPCResponse::<()>::error(RpcId::Null, RPCError::parse_error()),
+                        &config,
+                    );
+                    (r.unwrap_or_default(), None)
+                }
+            };
+
+        if let Err(e) = sink.send(reply).await {
+            log::debug!("{}", e);
+            break;
+        }
+        if let Some((id, producer)) = producer {
+            subscriptions.insert(id, producer);
+        }
+    }
+
+    log::debug!(
+        "WebSocket connection closed, cancelling {} subscriptions",
+        subscriptions.tasks.len()
+    );
+    drop(subscriptions);
+    // Sinks a handler kept would otherwise hold the writer open.
+    writer.abort();
+}
+
+/// Forwards events of the listed types, or of every type, to an `rpc.events` subscription.
+async fn forward_events(
+    mut events: broadcast::Receiver<Arc<ServerEvent>>,
+    kinds: Option<Vec<String>>,
+    sink: SubscriptionSink,
+) {
+    loop {
+        let event = match events.recv().await {
+            Ok(e) => e,
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                log::warn!("Event subscription {} missed {} events", sink.id, n);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        if kinds
+            .as_ref()
+            .is_some_and(|k| !k.iter().any(|k| k == event.kind()))
+        {
+            continue;
+        }
+        if sink.notify(event.as_ref()).await.is_err() {
+            break;
+        }
+    }
+}
+
+type Producer = Option<(u64, BoxFuture<'static, ()>)>;
+
+/// How one message is answered.
+enum Reply {
+    Now(String, Producer),
+    /// A call answered from its own task, so later messages such as `rpc.cancel`
+    /// are read while it runs.
+    Later(Option<RpcId>, BoxFuture<'static, Result<Option<String>>>),
+    /// A batch of notifications only.
+    Nothing,
+}
+
+async fn dispatch<H>(
+    text: &str,
+    handle: &Arc<H>,
+    config: &Arc<ServerConfig>,
+    sink: &WsSink,
+    subscriptions: &mut Subscriptions,
+) -> Result<Reply>
+where
+    H: Handle + Send + Sync + 'static,
+    H::Request: Debug,
+{
+    if config.too_deep(text.as_bytes()) {
+        return Err(anyhow!("Request nested deeper than {:?}", config.max_depth));
+    }
+    let body: Value = serde_json::from_str(text)?;
+    if body.is_array() {
+        return Ok(match _batch_handle(body, handle.as_ref(), config).await? {
+            Some(reply) => Reply::Now(reply, None),
+            None => Reply::Nothing,
+        });
+    }
+
+    match body.get("method").and_then(Value::as_str) {
+        Some(m @ ("rpc.on" | "rpc.off")) => {
+            let listen = m == "rpc.on";
+            let req: RPCRequest<Value> = serde_json::from_value(body)?;
+            let r = match serde_json::from_value::<Vec<String>>(req.params) {
+                Ok(methods) if listen => {
+                    subscriptions.listen(&config.broadcaster, sink, methods);
+                    RPCResponse::result(req.id.unwrap_or_default(), Some(true))
+                }
+                Ok(methods) => {
+                    subscriptions.unlisten(&methods);
+                    RPCResponse::result(req.id.unwrap_or_default(), Some(true))
+                }
+                Err(_) => {
+                    RPCResponse::error(req.id.unwrap_or_default(), RPCError::invalid_params())
+                }
+            };
+            Ok(Reply::Now(encode(r, config)?, None))
+        }
+        Some("rpc.cancel") => {
+            let req: RPCRequest<(RpcId,)> = serde_json::from_value(body)?;
+            let target = req.params.0;
+            let cancelled = subscriptions.cancel(&target);
+            if cancelled {
+                log::debug!("Cancelled WebSocket call {}", target);
+                let r = RPCResponse::<()>::error(target, RPCError::request_cancelled());
+                sink.send(encode(r, config)?).await?;
+            }
+            Ok(Reply::Now(
+                encode(
+                    RPCResponse::result(req.id.unwrap_or_default(), Some(cancelled)),
+                    config,
+                )?,
+                None,
+            ))
+        }
+        Some("rpc.unsubscribe") => {
+            let req: RPCRequest<(u64,)> = serde_json::from_value(body)?;
+            let removed = subscriptions.remove(req.params.0);
+            Ok(Reply::Now(
+                encode(
+                    RPCResponse::result(req.id.unwrap_or_default(), Some(removed)),
+                    config,
+                )?,
+                None,
+            ))
+        }
+        Some("rpc.events") if config.events.is_some() => {
+            let req: RPCRequest<Option<Vec<String>>> = serde_json::from_value(body)?;
+            let id = req.id.unwrap_or_default();
+            let allowed = match (&config.events, &config.authorizer) {
+                (Some(events), Some(_)) => authorize(config, "rpc.events").await.map(|_| events),
+                _ => Err(RPCError::forbidden()),
+            };
+            let events = match allowed {
+                Ok(events) => events.subscribe(),
+                Err(e) => {
+                    let r = RPCResponse::<()>::error(id, e);
+                    return Ok(Reply::Now(encode(r, config)?, None));
+                }
+            };
+            log::log!(config.call_log_level, "Get subscribe method: rpc.events");
+
+            let sub = subscriptions.next_id();
+            let sub_sink = SubscriptionSink {
+                id: sub,
+                method: String::from("rpc.events"),
+                sink: sink.clone(),
+            };
+            let producer = Box::pin(forward_events(events, req.params, sub_sink));
+            Ok(Reply::Now(
+                encode(RPCResponse::result(id, Some(sub)), config)?,
+                Some((sub, producer)),
+            ))
+        }
+        // Unlisted methods fall through to `_handle`, which rejects them.
+        Some(method) if handle.is_subscription(method) && config.method_allowed(method) => {
+            let req: RPCRequest<Option<H::Request>> = serde_json::from_value(body)?;
+            if let Err(e) = authorize(config, &req.method).await {
+                let r = RPCResponse::<()>::error(req.id.unwrap_or_default(), e);
+                return Ok(Reply::Now(encode(r, config)?, None));
+            }
+            log::log!(
+                config.call_log_level,
+                "Get subscribe method: {}",
+                &req.method
+            );
+
+            let id = subscriptions.next_id();
+            let sub_sink = SubscriptionSink {
+                id,
+                method: req.method.clone(),
+                sink: sink.clone(),
+            };
+            match handle.subscribe(&req.method, req.params, sub_sink).await {
+                Ok(producer) => Ok(Reply::Now(
+                    encode(
+                        RPCResponse::result(req.id.unwrap_or_default(), Some(id)),
+                        config,
+                    )?,
+                    Some((id, producer)),
+                )),
+                Err(e) => Ok(Reply::Now(
+                    encode(
+                        RPCResponse::<()>::error(req.id.unwrap_or_default(), e),
+                        config,
+                    )?,
+                    None,
+                )),
+            }
+        }
+        Some(method) if handle.has_follow_ups(method) && config.method_allowed(method) => {
+            let req: RPCRequest<Option<H::Request>> = serde_json::from_value(body)?;
+            if let Err(e) = authorize(config, &req.method).await {
+                let r = RPCResponse::<()>::error(req.id.unwrap_or_default(), e);
+                return Ok(Reply::Now(encode(r, config)?, None));
+            }
+            log::log!(
+                config.call_log_level,
+                "Get call method: {} (with follow-ups)",
+                &req.method
+            );
+
+            let id = req.id.unwrap_or_default();
+            let sink = FollowUpSink {
+                id: id.clone(),
+                method: req.method.clone(),
+                sink: sink.clone(),
+            };
+            match handle
+                .handle_with_follow_ups(&req.method, req.params, sink)
+                .await
+            {
+                Ok((result, producer)) => Ok(Reply::Now(
+                    encode(RPCResponse::result(id, result), config)?,
+                    Some((subscriptions.next_id(), producer)),
+                )),
+                Err(e) => Ok(Reply::Now(
+                    encode(RPCResponse::<()>::error(id, e), config)?,
+                    None,
+                )),
+            }
+        }
+        _ => {
+            let id = body.get("id").map(|_| request_id(&body));
+            let (handle, config) = (handle.clone(), config.clone());
+            // Its own task doesn't inherit the connection's context.
+            let ctx = RequestContext::current();
+            Ok(Reply::Later(
+                id,
+                Box::pin(async move {
+                    let r = _handle(body, handle.as_ref(), &config);
+                    match ctx {
+                        Some(ctx) => ctx.scope(r).await,
+                        None => r.await,
+                    }
+                }),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use async_trait::async_trait;
+    use futures::{future::BoxFuture, SinkExt, StreamExt};
+    use serde_json::{json, Value};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+    use tokio_tungstenite::{
+        tungstenite::{client::IntoClientRequest, Message},
+        MaybeTlsStream, WebSocketStream,
+    };
+
+    use crate::{
+        testing, Auth, Authorizer, Broadcaster, Handle, RPCError, RPCResult, RequestContext,
+        ServerConfig, ServerEvents, SubscriptionSink,
+    };
+
+    type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// Its `idle` subscription sends nothing until cancelled, counting those running.
+    #[derive(Default)]
+    struct Idle(Arc<AtomicUsize>);
+
+    /// Counts one running producer until dropped.
+    struct Running(Arc<AtomicUsize>);
+
+    impl Drop for Running {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    #[async_trait]
+    impl Handle for Idle {
+        type Request = Value;
+        type Response = Value;
+
+        async fn handle(&self, method: &str, req: Option<Value>) -> RPCResult<Option<Value>> {
+            testing::TestHandle.handle(method, req).await
+        }
+
+        fn is_subscription(&self, method: &str) -> bool {
+            method == "idle"
+        }
+
+        async fn subscribe(
+            &self,
+            _: &str,
+            _: Option<Value>,
+            _: SubscriptionSink,
+        ) -> RPCResult<BoxFuture<'static, ()>> {
+            let running = self.0.clone();
+            Ok(Box::pin(async move {
+                running.fetch_add(1, Ordering::SeqCst);
+                let _running = Running(running);
+                std::future::pending::<()>().await
+            }))
+        }
+    }
+
+    async fn connect(config: ServerConfig) -> Socket {
+        connect_to(testing::TestHandle, config).await
+    }
+
+    async fn connect_to<H>(handle: H, config: ServerConfig) -> Socket
+    where
+        H: Handle + Send + Sync + 'static,
+        H::Request: std::fmt::Debug,
+    {
+        let config = ServerConfig {
+            websocket: true,
+            ..config
+        };
+        open(testing::serve_handle(handle, config).await).await
+    }
+
+    async fn open(addr: SocketAddr) -> Socket {
+        let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}"))
+            .await
+            .unwrap();
+        ws
+    }
+
+    /// Waits up to 5s for `running` to reach `n`.
+    async fn wait_for(running: &AtomicUsize, n: usize) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while running.load(Ordering::SeqCst) != n {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    async fn send(ws: &mut Socket, id: u64, method: &str, params: Value) {
+        let req = json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params});
+        ws.send(Message::text(req.to_string())).await.unwrap();
+    }
+
+    async fn recv(ws: &mut Socket) -> Value {
+        let frame = tokio::time::timeout(Duration::from_secs(5), ws.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        serde_json::from_str(frame.to_text().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn cancels_a_slow_call() {
+        let mut ws = connect(ServerConfig::default()).await;
+        send(&mut ws, 1, "sleep", json!([60_000])).await;
+        send(&mut ws, 2, "rpc.cancel", json!([1])).await;
+
+        let cancelled = recv(&mut ws).await;
+        assert_eq!(cancelled["id"], 1);
+        assert_eq!(cancelled["error"]["code"], -32800);
+        let r = recv(&mut ws).await;
+        assert_eq!((&r["id"], &r["result"]), (&json!(2), &json!(true)));
+    }
+
+    #[tokio::test]
+    async fn refuses_a_duplicate_id_in_flight() {
+        let mut ws = connect(ServerConfig::default()).await;
+        send(&mut ws, 1, "sleep", json!([200])).await;
+        send(&mut ws, 1, "echo", json!(["again"])).await;
+
+        let refused = recv(&mut ws).await;
+        assert_eq!(refused["id"], 1);
+        assert_eq!(refused["error"]["code"], -32600);
+        let r = recv(&mut ws).await;
+        assert_eq!((&r["id"], &r["result"]), (&json!(1), &json!([200])));
+    }
+
+    #[tokio::test]
+    async fn sheds_calls_over_max_in_flight() {
+        let mut ws = connect(ServerConfig {
+            max_in_flight: Some(1),
+            ..Default::default()
+        })
+        .await;
+        send(&mut ws, 1, "sleep", json!([200])).await;
+        send(&mut ws, 2, "echo", json!(["busy"])).await;
+
+        let shed = recv(&mut ws).await;
+        assert_eq!(shed["id"], 2);
+        assert_eq!(shed["error"]["code"], -32000);
+        assert_eq!(recv(&mut ws).await["id"], 1);
+    }
+
+    #[tokio::test]
+    async fn subscriptions_end_with_their_connection() {
+        let handle = Idle::default();
+        let running = handle.0.clone();
+        let mut ws = connect_to(handle, ServerConfig::default()).await;
+        send(&mut ws, 1, "idle", json!([])).await;
+        send(&mut ws, 2, "idle", json!([])).await;
+        wait_for(&running, 2).await;
+
+        // Dropped without a close frame, as when the client goes away.
+        drop(ws);
+        wait_for(&running, 0).await;
+    }
+
+    #[tokio::test]
+    async fn disconnects_peers_that_stop_answering_pings() {
+        let config = ServerConfig {
+            websocket: true,
+            ws_ping_interval: Some(Duration::from_millis(50)),
+            ws_pong_timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let addr = testing::serve(config.clone()).await;
+
+        // Reading the socket raw never answers a ping.
+        let mut silent = TcpStream::connect(addr).await.unwrap();
+        let upgrade = "GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+            Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n";
+        silent.write_all(upgrade.as_bytes()).await.unwrap();
+        let started = tokio::time::Instant::now();
+        let mut received = vec![];
+        tokio::time::timeout(Duration::from_secs(5), silent.read_to_end(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert!(received.contains(&0x89), "was never pinged");
+
+        // Reading through tungstenite answers every ping, so the connection stays open.
+        let mut ws = connect(config).await;
+        let listened = tokio::time::timeout(Duration::from_millis(300), async {
+            while let Some(frame) = ws.next().await {
+                assert!(frame.unwrap().is_ping());
+            }
+        })
+        .await;
+        assert!(listened.is_err(), "was disconnected");
+        send(&mut ws, 1, "echo", json!([1])).await;
+        assert_eq!(recv(&mut ws).await["result"], json!([1]));
+    }
+
+    #[tokio::test]
+    async fn broadcasts_reach_every_listening_connection() {
+        let broadcaster = Broadcaster::default();
+        let addr = testing::serve(ServerConfig {
+            websocket: true,
+            broadcaster: broadcaster.clone(),
+            ..Default::default()
+        })
+        .await;
+        let mut listeners = vec![open(addr).await, open(addr).await];
+        for ws in &mut listeners {
+            send(ws, 1, "rpc.on", json!(["news"])).await;
+            assert_eq!(recv(ws).await["result"], true);
+        }
+
+        assert_eq!(broadcaster.send("news", &json!({"n": 1})).unwrap(), 2);
+        for ws in &mut listeners {
+            let n = recv(ws).await;
+            assert_eq!(
+                (&n["method"], &n["params"]),
+                (&json!("news"), &json!({"n": 1}))
+            );
+            assert!(n.get("id").is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_batches_are_answered_with_one_32600() {
+        let mut ws = connect(ServerConfig::default()).await;
+        ws.send(Message::text("[]")).await.unwrap();
+        let r = recv(&mut ws).await;
+        assert_eq!(
+            (&r["id"], &r["error"]["code"]),
+            (&Value::Null, &json!(-32600))
+        );
+    }
+
+    #[tokio::test]
+    async fn notifications_get_no_frame_back() {
+        let mut ws = connect(ServerConfig::default()).await;
+        for method in ["echo", "fail"] {
+            let note = json!({"jsonrpc": "2.0", "method": method, "params": [1]});
+            ws.send(Message::text(note.to_string())).await.unwrap();
+        }
+        send(&mut ws, 3, "sleep", json!([50])).await;
+
+        // Frames go out as calls finish, so had either note been answered it
+        // would have come first.
+        let r = recv(&mut ws).await;
+        assert_eq!((&r["id"], &r["result"]), (&json!(3), &json!([50])));
+        let more = tokio::time::timeout(Duration::from_millis(100), ws.next()).await;
+        assert!(more.is_err(), "{more:?}");
+    }
+
+    #[tokio::test]
+    async fn concurrent_replies_arrive_as_whole_frames() {
+        let mut ws = connect(ServerConfig::default()).await;
+        // Large enough that frames written over each other would be caught mid-way.
+        let payload = |id: u64| id.to_string().repeat(20_000);
+        for id in 1..=100 {
+            send(&mut ws, id, "echo", json!([payload(id)])).await;
+        }
+
+        let mut seen = std::collections::HashSet::new();
+        for _ in 1..=100 {
+            let r = recv(&mut ws).await;
+            let id = r["id"].as_u64().unwrap();
+            assert_eq!(r["result"], json!([payload(id)]));
+            assert!(seen.insert(id), "{id} answered twice");
+        }
+    }
+
+    /// Lets only the `admin` token watch `rpc.events`.
+    struct AdminEvents;
+
+    #[async_trait]
+    impl Authorizer for AdminEvents {
+        async fn authorize(&self, method: &str, ctx: &RequestContext) -> RPCResult<()> {
+            let admin = Auth::bearer("admin").header_value();
+            match ctx.headers.get("Authorization") {
+                Some(v) if method == "rpc.events" && *v != admin.as_str() => {
+                    Err(RPCError::forbidden())
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    async fn open_as(addr: SocketAddr, token: &str) -> Socket {
+        let mut request = format!("ws://{addr}").into_client_request().unwrap();
+        let auth = Auth::bearer(token).header_value().parse().unwrap();
+        request.headers_mut().insert("Authorization", auth);
+        let (ws, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        ws
+    }
+
+    #[tokio::test]
+    async fn only_authorized_admins_watch_events() {
+        let addr = testing::serve(ServerConfig {
+            websocket: true,
+            auth: vec![Auth::bearer("admin"), Auth::bearer("user")],
+            authorizer: Some(Arc::new(AdminEvents)),
+            events: Some(ServerEvents::default()),
+            ..Default::default()
+        })
+        .await;
+        let mut user = open_as(addr, "user").await;
+        send(&mut user, 1, "rpc.events", json!(["dispatched"])).await;
+        let refused = recv(&mut user).await;
+        assert_eq!(refused["error"]["code"], RPCError::forbidden().code);
+
+        let mut admin = open_as(addr, "admin").await;
+        send(&mut admin, 1, "rpc.events", json!(["dispatched"])).await;
+        let sub = recv(&mut admin).await["result"].clone();
+        send(&mut user, 2, "echo", json!([1])).await;
+        assert_eq!(recv(&mut user).await["result"], json!([1]));
+
+        let event = recv(&mut admin).await;
+        assert_eq!(event["method"], "rpc.events");
+        assert_eq!(event["params"]["subscription"], sub);
+        let event = &event["params"]["result"];
+        assert_eq!(
+            (&event["type"], &event["method"]),
+            (&json!("dispatched"), &json!("echo"))
+        );
+    }
+}
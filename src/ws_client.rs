@@ -0,0 +1,254 @@
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use bytes::Bytes;
+use futures::{stream::SplitSink, SinkExt, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    net::TcpStream,
+    sync::{broadcast, Mutex},
+    task::JoinHandle,
+};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+use crate::{ws::Keepalive, PendingRequests, RPCError, RPCRequest, RPCResponse, RPCResult, RpcId};
+
+/// A notification pushed by the server, i.e. a message with a method but no id.
+#[derive(Deserialize)]
+struct Notification {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+/// A JSON-RPC client over one WebSocket connection.
+///
+/// Responses are matched to calls by id; everything the server pushes on its own
+/// is delivered through [`WsClient::notifications`].
+pub struct WsClient {
+    sink: Arc<Mutex<SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>>>,
+    pending: PendingRequests,
+    notifications: broadcast::Receiver<RPCRequest<Value>>,
+    next_id: AtomicI64,
+    reader: JoinHandle<()>,
+}
+
+impl WsClient {
+    pub async fn connect(url: &str) -> Result<Self> {
+        Self::open(url, Keepalive::new(None, Duration::ZERO)).await
+    }
+
+    /// Like [`WsClient::connect`], also pinging the server once it has been quiet for
+    /// `interval`, and closing the connection if nothing comes back within `timeout`.
+    /// Pings from the server are answered either way.
+    pub async fn connect_with_keepalive(
+        url: &str,
+        interval: Duration,
+        timeout: Duration,
+    ) -> Result<Self> {
+        Self::open(url, Keepalive::new(Some(interval), timeout)).await
+    }
+
+    async fn open(url: &str, mut keepalive: Keepalive) -> Result<Self> {
+        let (ws, _) = connect_async(url).await?;
+        let (sink, mut stream) = ws.split();
+        let sink = Arc::new(Mutex::new(sink));
+
+        let pending = PendingRequests::new();
+        let (tx, notifications) = broadcast::channel(1024);
+
+        let reader = {
+            let pending = pending.clone();
+            let sink = sink.clone();
+            tokio::spawn(async move {
+                loop {
+                    let msg = tokio::select! {
+                        msg = stream.next() => match msg {
+                            Some(msg) => msg,
+                            None => break,
+                        },
+                        ping = keepalive.tick() => {
+                            if !ping {
+                                log::debug!("WebSocket server stopped answering pings, closing");
+                                // Later calls then fail to send instead of waiting forever.
+                                let _ = sink.lock().await.close().await;
+                                break;
+                            }
+                            let ping = Message::Ping(Bytes::new());
+                            if sink.lock().await.send(ping).await.is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+                    };
+                    keepalive.heard();
+                    let text = match msg {
+                        Ok(Message::Text(t)) => t,
+                        Ok(Message::Close(_)) => break,
+                        Ok(_) => continue,
+                        Err(e) => {
+                            log::debug!("WebSocket connection error: {}", e);
+                            break;
+                        }
+                    };
+                    let Ok(v) = serde_json::from_str::<Value>(&text) else {
+                        log::warn!("Unreadable WebSocket message: {}", text.as_str());
+                        continue;
+                    };
+
+                    match v.get("id") {
+                        None => match serde_json::from_value::<Notification>(v) {
+                            Ok(n) => {
+                                let _ = tx.send(RPCRequest {
+                                    jsonrpc: n.jsonrpc,
+                                    method: n.method,
+                                    params: n.params,
+                                    id: None,
+                                });
+                            }
+                            Err(e) => log::warn!("Malformed notification: {}", e),
+                        },
+                        Some(id) => {
+                            let id = RpcId::deserialize(id).unwrap_or_default();
+                            if pending.resolve(&id, v).is_err() {
+                                log::warn!("Response for unknown request id {}", id);
+                            }
+                        }
+                    }
+                }
+                // Fails every call still waiting, and later ones without waiting.
+                pending.close();
+            })
+        };
+
+        Ok(Self {
+            sink,
+            pending,
+            notifications,
+            next_id: AtomicI64::new(1),
+            reader,
+        })
+    }
+
+    pub async fn call<P, R>(&self, method: &str, params: &P) -> RPCResult<Option<R>>
+    where
+        R: for<'de> Deserialize<'de> + Debug,
+        P: Serialize + Clone,
+    {
+        let id = RpcId::Number(self.next_id.fetch_add(1, Ordering::SeqCst));
+        let req = RPCRequest {
+            id: Some(id.clone()),
+            ..RPCRequest::new(method, params)
+        };
+        let s =
+            serde_json::to_string(&req).map_err(|e| RPCError::internal_error(format!("{e:?}")))?;
+
+        let rx = self.pending.register(id);
+        if let Err(e) = self.sink.lock().await.send(Message::Text(s.into())).await {
+            return Err(RPCError::internal_error(format!("{e:?}")));
+        }
+
+        let v = rx
+            .recv(None)
+            .await
+            .map_err(|_| RPCError::internal_error(String::from("WebSocket connection closed")))?;
+        let resp: RPCResponse<R> =
+            serde_json::from_value(v).map_err(|e| RPCError::internal_error(format!("{e:?}")))?;
+        resp.into_result()
+    }
+
+    /// Notifications pushed by the server from now on, including subscription and
+    /// `rpc.on` broadcasts. Each stream sees every notification; one that falls more
+    /// than 1024 behind skips the oldest. Ends when the connection closes.
+    pub fn notifications(&self) -> impl Stream<Item = RPCRequest<Value>> + Send + Unpin + 'static {
+        futures::stream::unfold(self.notifications.resubscribe(), |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(n) => return Some((n, rx)),
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        log::warn!("Notification stream missed {} messages", n);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+impl Drop for WsClient {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{testing, Broadcaster, ServerConfig};
+
+    #[tokio::test]
+    async fn calls_fail_once_the_connection_is_gone() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            ws.close(None).await.unwrap();
+        });
+
+        let client = WsClient::connect(&format!("ws://{addr}")).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !client.pending.is_closed() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let r = tokio::time::timeout(Duration::from_secs(5), client.call::<_, Value>("m", &()))
+            .await
+            .unwrap();
+        assert!(r.is_err());
+    }
+
+    #[tokio::test]
+    async fn notifications_pushed_by_the_server_are_streamed() {
+        let broadcaster = Broadcaster::default();
+        let addr = testing::serve(ServerConfig {
+            websocket: true,
+            broadcaster: broadcaster.clone(),
+            ..Default::default()
+        })
+        .await;
+        let client = WsClient::connect(&format!("ws://{addr}")).await.unwrap();
+        let mut notifications = client.notifications();
+        let listening = client.call::<_, bool>("rpc.on", &["news"]).await.unwrap();
+        assert_eq!(listening, Some(true));
+
+        broadcaster.send("news", &json!({"n": 1})).unwrap();
+        let n = tokio::time::timeout(Duration::from_secs(5), notifications.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((n.method.as_str(), &n.params), ("news", &json!({"n": 1})));
+        assert!(n.is_notification());
+        // Calls are not mistaken for notifications.
+        let echoed = client.call::<_, Value>("echo", &[1]).await.unwrap();
+        assert_eq!(echoed, Some(json!([1])));
+        let next = tokio::time::timeout(Duration::from_millis(50), notifications.next()).await;
+        assert!(next.is_err());
+    }
+}